@@ -85,11 +85,19 @@ async function buildKyberContext() {
       { text: selected, selected: true },
       { text: post, selected: false },
     ],
+    language_id: editor.getGrammar().scopeName.replace(/^source\./, ""),
+    file_path: editor.getPath(),
+    // Buffer positions and JavaScript strings both count UTF-16 code units.
+    position_encoding: "utf-16",
   };
 }
 
 async function retrieveSuggestions(context) {
-  return (await doRpc("suggest", { context })).suggestions;
+  const { suggestions, errors } = await doRpc("suggest", { context });
+  for (const { id, error } of errors) {
+    console.error(`Refactoring ${id} failed: ${error.message}`);
+  }
+  return suggestions;
 }
 
 async function doRpc(rpc, input) {
@@ -155,7 +163,21 @@ function Suggestion({ suggestion }) {
 
 async function applySuggestion(suggestion) {
   const context = await buildKyberContext();
-  const result = await doRpc("perform", { context, id: suggestion.id });
+
+  const inputs = {};
+  for (const input of suggestion.inputs) {
+    const value = await promptForInput(input.prompt);
+    if (value == null) return;
+    inputs[input.key] = value;
+  }
+
+  const result = await doRpc("perform", { context, id: suggestion.id, inputs });
+  if (result.error != null) {
+    atom.notifications.addError(`Could not perform ${suggestion.name}`, {
+      detail: JSON.stringify(result.error),
+    });
+    return;
+  }
 
   const editor = atom.workspace.getActiveTextEditor();
   editor.transact(0, () => {
@@ -167,6 +189,17 @@ async function applySuggestion(suggestion) {
         times(mutation.backspace, () => editor.backspace());
       } else if (mutation.insert != null) {
         editor.insertText(mutation.insert);
+      } else if (mutation.select != null) {
+        const buffer = editor.getBuffer();
+        editor.setSelectedBufferRange([
+          buffer.positionForCharacterIndex(mutation.select.start),
+          buffer.positionForCharacterIndex(mutation.select.end),
+        ]);
+      } else if (mutation.cursor != null) {
+        const buffer = editor.getBuffer();
+        editor.setCursorBufferPosition(
+          buffer.positionForCharacterIndex(mutation.cursor)
+        );
       } else {
         console.error({mutation});
         throw new Error(`Unrecognized mutation`);
@@ -176,6 +209,32 @@ async function applySuggestion(suggestion) {
   });
 }
 
+// Asks for a line of text in a modal panel, resolving to null if it's cancelled.
+function promptForInput(prompt) {
+  const item = document.createElement("div");
+  const label = document.createElement("label");
+  label.textContent = prompt;
+  const editor = atom.workspace.buildTextEditor({ mini: true });
+  item.append(label, editor.getElement());
+
+  const previouslyFocused = document.activeElement;
+  const panel = atom.workspace.addModalPanel({ item });
+  editor.getElement().focus();
+
+  return new Promise((resolve) => {
+    const finish = (value) => {
+      commands.dispose();
+      panel.destroy();
+      if (previouslyFocused != null) previouslyFocused.focus();
+      resolve(value);
+    };
+    const commands = atom.commands.add(editor.getElement(), {
+      "core:confirm": () => finish(editor.getText()),
+      "core:cancel": () => finish(null),
+    });
+  });
+}
+
 function times(count, fn) {
   for (let i = 0; i < count; i++) {
     fn();
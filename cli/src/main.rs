@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
 use structopt::StructOpt;
 
 mod refactorings;
-use refactorings::Mutation;
+use refactorings::{
+    write_edits, Coordinates, Input, KybError, Mutation, PerformError, Position, TextEdits,
+};
 
 #[derive(StructOpt, Debug)]
 struct Options {
@@ -13,6 +16,37 @@ struct Options {
 #[derive(StructOpt, Debug)]
 enum Command {
     Rpc(RpcMethod),
+    /// Performs a refactoring on a file, writing its edits to every file it changes.
+    Apply(ApplyOptions),
+}
+
+#[derive(StructOpt, Debug)]
+struct ApplyOptions {
+    /// Id of the refactoring.
+    id: String,
+    file: PathBuf,
+    /// Byte offset of the cursor, or the start of the selection.
+    #[structopt(long, default_value = "0")]
+    cursor: usize,
+    /// Byte offset of the end of the selection, if anything is selected.
+    #[structopt(long)]
+    selection_end: Option<usize>,
+    /// Language of the file, like `rust`, if it can't be told from the file's extension.
+    #[structopt(long)]
+    language: Option<String>,
+    /// Directory the refactoring may search for other files, if not the enclosing Git repository
+    /// or Cargo workspace of the file.
+    #[structopt(long)]
+    workspace_root: Option<PathBuf>,
+    /// Input for the refactoring, as `key=value`.
+    #[structopt(long = "input", parse(try_from_str = parse_input))]
+    inputs: Vec<(String, String)>,
+}
+
+fn parse_input(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Expected key=value, found {:?}", s))
 }
 
 #[derive(StructOpt, Debug)]
@@ -21,22 +55,116 @@ enum RpcMethod {
     Perform,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let options = Options::from_args();
+fn main() {
+    if let Err(e) = run(Options::from_args()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>> {
     match options.command {
         Command::Rpc(RpcMethod::Suggest) => {
             let request: SuggestRequest = serde_json::from_reader(std::io::stdin())?;
-            let suggestions = suggestions_for_context(&request.context);
-            serde_json::to_writer(std::io::stdout(), &SuggestResponse { suggestions })?;
+            let response = suggestions_for_context(&request.context);
+            serde_json::to_writer(std::io::stdout(), &response)?;
         }
         Command::Rpc(RpcMethod::Perform) => {
             let request: PerformRequest = serde_json::from_reader(std::io::stdin())?;
-            let refactoring = refactorings::all()
-                .find(|r| r.id() == request.id)
-                .ok_or_else(|| format!("Could not find refactoring with id {}", request.id))?;
-            let mutations = refactoring.perform(&request.context)?;
-            serde_json::to_writer(std::io::stdout(), &PerformResponse { mutations })?;
+            let refactoring = find_refactoring(&request.id)?;
+            let response = match request.output {
+                Output::Keystrokes => refactoring
+                    .perform(&request.context, &request.inputs)
+                    .map(PerformResponse::Mutations),
+                Output::Edits => refactoring
+                    .perform_edits(&request.context, &request.inputs, request.coordinates)
+                    .map(PerformResponse::TextEdits),
+            };
+            let response = response.unwrap_or_else(PerformResponse::Error);
+            serde_json::to_writer(std::io::stdout(), &response)?;
+        }
+        Command::Apply(options) => apply(options)?,
+    }
+
+    Ok(())
+}
+
+/// The refactoring with `id`, or why it couldn't be found, including any that failed to load.
+fn find_refactoring(id: &str) -> Result<Box<dyn refactorings::Refactoring>, String> {
+    let mut load_errors = Vec::new();
+    for r in refactorings::all() {
+        match r {
+            Ok(r) if r.id() == id => return Ok(r),
+            Ok(_) => {}
+            Err(e) => load_errors.push(e.to_string()),
+        }
+    }
+
+    let mut message = format!("Could not find refactoring with id {}", id);
+    for e in load_errors {
+        message += "\n";
+        message += &e;
+    }
+    Err(message)
+}
+
+fn apply(options: ApplyOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let refactoring = find_refactoring(&options.id)?;
+
+    let text = std::fs::read_to_string(&options.file)?;
+    let file = std::fs::canonicalize(&options.file)?;
+    let workspace_root = match options.workspace_root {
+        Some(root) => Some(std::fs::canonicalize(root)?),
+        None => refactorings::root_of(&file),
+    };
+    let selection = options.cursor..options.selection_end.unwrap_or(options.cursor);
+    if selection.start > selection.end
+        || !text.is_char_boundary(selection.start)
+        || !text.is_char_boundary(selection.end)
+    {
+        return Err(format!("Selection {:?} is not in the file", selection).into());
+    }
+    let region = |text: &str, selected| ContentRegion {
+        text: text.to_string(),
+        selected,
+    };
+    let context = EditorContext {
+        contents: vec![
+            region(&text[..selection.start], false),
+            region(&text[selection.clone()], true),
+            region(&text[selection.end..], false),
+        ],
+        language_id: options.language.or_else(|| {
+            refactorings::language_of_path(&options.file.to_string_lossy()).map(str::to_string)
+        }),
+        file_path: Some(file.display().to_string()),
+        // Without a root, refactorings that look at other files refuse to rather than guessing.
+        workspace_root: workspace_root.map(|root| root.display().to_string()),
+        position_encoding: PositionEncoding::Utf8,
+    };
+
+    if !refactoring.available_in(&context) {
+        return Err(format!(
+            "{} is not available for {}",
+            options.id,
+            options.file.display()
+        )
+        .into());
+    }
+
+    let inputs = options.inputs.into_iter().collect();
+    let edits = refactoring.perform_edits(&context, &inputs, Coordinates::Offset)?;
+
+    let buffer = (options.file.display().to_string(), edits.edits);
+    for (path, file_edits) in std::iter::once(buffer).chain(edits.files) {
+        if !file_edits.is_empty() {
+            write_edits(path.as_ref(), &file_edits)?;
+            println!("{}", path);
+        }
+    }
+    if let Some(selection) = edits.selection {
+        if let (Position::Offset(start), Position::Offset(end)) = (selection.start, selection.end) {
+            eprintln!("Selection: {}..{}", start, end);
         }
     }
 
@@ -52,6 +180,15 @@ struct SuggestRequest {
 #[derive(Serialize, Debug)]
 struct SuggestResponse {
     suggestions: Vec<Refactoring>,
+    /// Refactorings that could not be checked because of a bug in their script.
+    errors: Vec<RefactoringError>,
+}
+
+#[derive(Serialize, Debug)]
+struct RefactoringError {
+    /// Id of the refactoring, or its file if it could not be loaded.
+    id: String,
+    error: KybError,
 }
 
 #[derive(Deserialize, Debug)]
@@ -59,17 +196,49 @@ struct SuggestResponse {
 struct PerformRequest {
     context: EditorContext,
     id: String,
+    #[serde(default)]
+    inputs: HashMap<String, String>,
+    #[serde(default)]
+    output: Output,
+    /// Positions of the edits when `output` is `edits`.
+    #[serde(default)]
+    coordinates: Coordinates,
+}
+
+/// How a performed refactoring's changes are described.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum Output {
+    /// Keystrokes relative to the selection.
+    #[default]
+    Keystrokes,
+    /// Edits to ranges of the buffer.
+    Edits,
 }
 
 #[derive(Serialize, Debug)]
-struct PerformResponse {
-    mutations: Vec<Mutation>,
+#[serde(rename_all = "snake_case")]
+enum PerformResponse {
+    Mutations(Vec<Mutation>),
+    TextEdits(TextEdits),
+    Error(PerformError),
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct EditorContext {
     contents: Vec<ContentRegion>,
+    /// Language of the buffer, like `rust`, if the editor knows it.
+    #[serde(default)]
+    language_id: Option<String>,
+    #[serde(default)]
+    file_path: Option<String>,
+    /// Directory the refactoring can find other files in.
+    #[serde(default)]
+    workspace_root: Option<String>,
+    /// Unit of the counts and positions sent back.
+    #[serde(default)]
+    position_encoding: PositionEncoding,
 }
 
 impl EditorContext {
@@ -84,6 +253,31 @@ impl EditorContext {
     }
 }
 
+/// How an editor counts positions in its buffers.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    /// Bytes of UTF-8.
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// UTF-16 code units, as in JavaScript strings.
+    #[serde(rename = "utf-16")]
+    Utf16,
+    /// Chars.
+    #[serde(rename = "utf-32")]
+    Utf32,
+}
+
+impl PositionEncoding {
+    pub fn len(self, text: &str) -> usize {
+        match self {
+            PositionEncoding::Utf8 => text.len(),
+            PositionEncoding::Utf16 => text.encode_utf16().count(),
+            PositionEncoding::Utf32 => text.chars().count(),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ContentRegion<S = String> {
@@ -91,15 +285,41 @@ pub struct ContentRegion<S = String> {
     selected: bool,
 }
 
-fn suggestions_for_context(_context: &EditorContext) -> Vec<Refactoring> {
-    refactorings::all()
-        .filter(|r| r.applies_to(_context))
-        .map(|r| Refactoring {
-            name: r.name(),
-            description: r.description(),
-            id: r.id(),
-        })
-        .collect()
+fn suggestions_for_context(context: &EditorContext) -> SuggestResponse {
+    let mut suggestions = Vec::new();
+    let mut errors = Vec::new();
+
+    for r in refactorings::all() {
+        let r = match r {
+            Ok(r) => r,
+            Err(e) => {
+                errors.push(RefactoringError {
+                    id: e.file.to_string(),
+                    error: KybError::Load(e.message),
+                });
+                continue;
+            }
+        };
+        if !r.available_in(context) {
+            continue;
+        }
+
+        match r.applies_to(context) {
+            Ok(true) => suggestions.push(Refactoring {
+                name: r.name(),
+                description: r.description(),
+                id: r.id(),
+                inputs: r.inputs(),
+            }),
+            Ok(false) => {}
+            Err(error) => errors.push(RefactoringError { id: r.id(), error }),
+        }
+    }
+
+    SuggestResponse {
+        suggestions,
+        errors,
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -107,4 +327,5 @@ struct Refactoring {
     name: String,
     description: String,
     id: String,
+    inputs: Vec<Input>,
 }
@@ -0,0 +1,97 @@
+/// The words of an identifier in any common case, like `["http", "Server", "2"]` for
+/// `http_Server-2` or `["HTTP", "Server"]` for `HTTPServer`.
+///
+/// Words are separated by anything that is not a letter or digit, and by a change to uppercase
+/// inside a word. A run of capitals followed by a lowercase letter ends before its last capital.
+pub fn words(s: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let chars = s.char_indices().collect::<Vec<_>>();
+
+    let mut start = None;
+    for (i, &(offset, c)) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&s[start..offset]);
+            }
+            continue;
+        }
+
+        if let Some(word_start) = start {
+            let prev = chars[i - 1].1;
+            let next = chars.get(i + 1).map(|&(_, c)| c);
+            let starts_word = c.is_uppercase()
+                && (prev.is_lowercase()
+                    || prev.is_numeric()
+                    || (prev.is_uppercase() && next.is_some_and(char::is_lowercase)));
+
+            if starts_word {
+                words.push(&s[word_start..offset]);
+                start = Some(offset);
+            }
+        } else {
+            start = Some(offset);
+        }
+    }
+    if let Some(start) = start {
+        words.push(&s[start..]);
+    }
+
+    words
+}
+
+pub fn snake_case(s: &str) -> String {
+    words(s)
+        .iter()
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+pub fn camel_case(s: &str) -> String {
+    let mut words = words(s).into_iter();
+    let first = words.next().map(str::to_lowercase).unwrap_or_default();
+    first + &words.map(capitalize).collect::<String>()
+}
+
+pub fn pascal_case(s: &str) -> String {
+    words(s).into_iter().map(capitalize).collect()
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_() {
+        assert_eq!(words("foo_bar"), vec!["foo", "bar"]);
+        assert_eq!(words("fooBar"), vec!["foo", "Bar"]);
+        assert_eq!(words("FooBar"), vec!["Foo", "Bar"]);
+        assert_eq!(words("FOO_BAR"), vec!["FOO", "BAR"]);
+        assert_eq!(words("HTTPServer"), vec!["HTTP", "Server"]);
+        assert_eq!(words("__foo--bar baz__"), vec!["foo", "bar", "baz"]);
+        assert_eq!(words("utf8Decoder"), vec!["utf8", "Decoder"]);
+        assert_eq!(words("x2Y"), vec!["x2", "Y"]);
+        assert_eq!(words("ÉtéÀ"), vec!["Été", "À"]);
+        assert_eq!(words(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(snake_case("HTTPServer"), "http_server");
+        assert_eq!(camel_case("http_server"), "httpServer");
+        assert_eq!(camel_case("HTTPServer"), "httpServer");
+        assert_eq!(pascal_case("http_server"), "HttpServer");
+        assert_eq!(pascal_case(""), "");
+    }
+}
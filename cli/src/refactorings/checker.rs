@@ -0,0 +1,910 @@
+use super::{
+    parser::{Diagnostic, Expr, Span, Stmt, TopLevel},
+    script::Script,
+    syntax,
+};
+use std::{collections::HashMap, ops::Range};
+
+/// Directives every refactoring has to have.
+const REQUIRED_DIRECTIVES: &[&str] = &["id", "name"];
+const DIRECTIVES: &[&str] = &["id", "name", "description", "languages", "files"];
+const BUILTINS: &[&str] = &[
+    "find",
+    "find_selected",
+    "find_in_file",
+    "input_string",
+    "abort",
+    "select",
+    "cursor_at",
+    "files",
+    "balanced",
+    "node",
+];
+
+/// Errors a script would hit when run that can be found without running it.
+///
+/// Checks that every variable is defined before it is used, that builtin functions and methods
+/// exist and get the right number and kinds of arguments, and that patterns are only built where
+/// a pattern is expected.
+pub fn check(script: &Script) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        errors: Vec::new(),
+        span: 0..0,
+        in_function: false,
+    };
+
+    let mut scope = HashMap::new();
+    for top_level in script.top_levels() {
+        match top_level {
+            TopLevel::Import(import) => {
+                for ident in &import.idents {
+                    scope.insert(ident.clone(), Kind::Unknown);
+                }
+            }
+            TopLevel::Directive(_) => {}
+            TopLevel::Function(f) => {
+                checker.span = f.span.0.clone();
+                if BUILTINS.contains(&f.name.as_str()) {
+                    checker.error(
+                        f.span.0.clone(),
+                        format!("Can't redefine builtin function {:?}", f.name),
+                    );
+                }
+                scope.insert(f.name.clone(), Kind::Function(f.params.len()));
+
+                let mut body_scope = scope.clone();
+                for param in &f.params {
+                    body_scope.insert(param.clone(), Kind::Unknown);
+                }
+                checker.in_function = true;
+                checker.block(&f.body, body_scope);
+                checker.in_function = false;
+            }
+            TopLevel::Stmt(stmt, Span(span)) => {
+                checker.span = span.clone();
+                checker.stmt(stmt, &mut scope);
+            }
+        }
+    }
+
+    checker.errors
+}
+
+/// Errors in the directives of a script used as a refactoring.
+pub fn check_directives(script: &Script) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+
+    for top_level in script.top_levels() {
+        if let TopLevel::Directive(d) = top_level {
+            if !DIRECTIVES.contains(&d.name.as_str()) {
+                errors.push(Diagnostic::new(
+                    d.span.0.clone(),
+                    format!("Unknown directive @{}", d.name),
+                ));
+            }
+        }
+    }
+
+    for name in REQUIRED_DIRECTIVES {
+        if script.directive(name).is_none() {
+            errors.push(Diagnostic::new(
+                0..0,
+                format!("Missing @{} directive", name),
+            ));
+        }
+    }
+
+    errors
+}
+
+/// What a value is known to be before the script runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    String,
+    Bool,
+    Regex,
+    Range,
+    Ranges,
+    Edits,
+    Number,
+    List,
+    Map,
+    /// A function declared with `fn`, taking this many arguments.
+    Function(usize),
+    /// Only known once the script runs.
+    Unknown,
+}
+
+impl Kind {
+    fn describe(self) -> &'static str {
+        match self {
+            Kind::String => "string",
+            Kind::Bool => "bool",
+            Kind::Regex => "regex",
+            Kind::Range => "range",
+            Kind::Ranges => "list of ranges",
+            Kind::Edits => "edits",
+            Kind::Number => "number",
+            Kind::List => "list",
+            Kind::Map => "map",
+            Kind::Function(_) => "function",
+            Kind::Unknown => "unknown",
+        }
+    }
+
+    /// Whether a value of this kind can be used where `expected` is.
+    fn fits(self, expected: Kind) -> bool {
+        self == Kind::Unknown
+            || self == expected
+            || (self == Kind::Range && expected == Kind::String)
+            || (self == Kind::Ranges && expected == Kind::List)
+    }
+}
+
+struct Checker {
+    errors: Vec<Diagnostic>,
+    /// Span of the innermost statement or call being checked, for errors in nodes without one.
+    span: Range<usize>,
+    in_function: bool,
+}
+
+impl Checker {
+    fn error(&mut self, span: Range<usize>, message: String) {
+        self.errors.push(Diagnostic::new(span, message));
+    }
+
+    fn span_of(&self, expr: &Expr) -> Range<usize> {
+        match expr {
+            Expr::Ident(_, span)
+            | Expr::FnCall(_, _, span)
+            | Expr::MethodCall(_, _, _, span)
+            | Expr::Index(_, _, span) => span.0.clone(),
+            _ => self.span.clone(),
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, scope: &mut HashMap<String, Kind>) {
+        match stmt {
+            Stmt::Assignment(ident, e) => {
+                let kind = self.value(e, scope);
+                scope.insert(ident.clone(), kind);
+            }
+
+            Stmt::Expr(e) => {
+                self.value(e, scope);
+            }
+
+            Stmt::ForLoop(ident, e, body) => {
+                let item = match self.value(e, scope) {
+                    Kind::Ranges => Kind::Range,
+                    Kind::List | Kind::Unknown => Kind::Unknown,
+                    kind => {
+                        self.error(
+                            self.span_of(e),
+                            format!("Expected list, found {}", kind.describe()),
+                        );
+                        Kind::Unknown
+                    }
+                };
+
+                let mut scope = scope.clone();
+                scope.insert(ident.clone(), item);
+                self.block(body, scope);
+            }
+
+            Stmt::If(condition, body, else_body) => {
+                self.expect(condition, scope, Kind::Bool);
+                self.block(body, scope.clone());
+                self.block(else_body, scope.clone());
+            }
+
+            Stmt::Return(e, span) => {
+                if !self.in_function {
+                    self.error(
+                        span.0.clone(),
+                        "Can only return from a function".to_string(),
+                    );
+                }
+                self.value(e, scope);
+            }
+        }
+    }
+
+    fn block(&mut self, body: &[Stmt], mut scope: HashMap<String, Kind>) {
+        for stmt in body {
+            self.stmt(stmt, &mut scope);
+        }
+    }
+
+    fn expect(&mut self, expr: &Expr, scope: &HashMap<String, Kind>, expected: Kind) {
+        let kind = self.value(expr, scope);
+        if !kind.fits(expected) {
+            self.error(
+                self.span_of(expr),
+                format!(
+                    "Expected {}, found {}",
+                    expected.describe(),
+                    kind.describe()
+                ),
+            );
+        }
+    }
+
+    fn arity(&mut self, name: &str, args: &[Expr], span: &Span, allowed: Range<usize>) {
+        if allowed.contains(&args.len()) {
+            return;
+        }
+
+        let expected = if allowed == (1..2) {
+            "1 argument".to_string()
+        } else if allowed.len() == 1 {
+            format!("{} arguments", allowed.start)
+        } else {
+            format!("{} to {} arguments", allowed.start, allowed.end - 1)
+        };
+        self.error(
+            span.0.clone(),
+            format!("{} takes {}, found {}", name, expected, args.len()),
+        );
+    }
+
+    /// Kind of the value `expr` evaluates to.
+    fn value(&mut self, expr: &Expr, scope: &HashMap<String, Kind>) -> Kind {
+        match expr {
+            Expr::StringLiteral(_) => Kind::String,
+            Expr::Regex(_) => Kind::Regex,
+            Expr::Bool(_) => Kind::Bool,
+            Expr::Number(_) => Kind::Number,
+
+            Expr::List(items) => {
+                for item in items {
+                    self.value(item, scope);
+                }
+                Kind::List
+            }
+            Expr::Map(entries) => {
+                for (key, value) in entries {
+                    self.expect(key, scope, Kind::String);
+                    self.value(value, scope);
+                }
+                Kind::Map
+            }
+
+            Expr::Index(obj, index, span) => match self.value(obj, scope) {
+                Kind::Ranges => {
+                    self.expect(index, scope, Kind::Number);
+                    Kind::Range
+                }
+                Kind::List => {
+                    self.expect(index, scope, Kind::Number);
+                    Kind::Unknown
+                }
+                Kind::Map => {
+                    self.expect(index, scope, Kind::String);
+                    Kind::Unknown
+                }
+                Kind::Unknown => {
+                    self.value(index, scope);
+                    Kind::Unknown
+                }
+                kind => {
+                    self.error(span.0.clone(), format!("Can't index {}", kind.describe()));
+                    Kind::Unknown
+                }
+            },
+
+            Expr::Ident(ident, span) => match scope.get(ident) {
+                Some(kind) => *kind,
+                None => {
+                    self.error(span.0.clone(), format!("Unknown variable {:?}", ident));
+                    Kind::Unknown
+                }
+            },
+
+            Expr::DotAccess(obj, _) => {
+                self.expect(obj, scope, Kind::Range);
+                Kind::Unknown
+            }
+
+            Expr::FnCall(func, args, span) => {
+                let outer = std::mem::replace(&mut self.span, span.0.clone());
+                let kind = self.fn_call(func, args, span, scope);
+                self.span = outer;
+                kind
+            }
+
+            Expr::MethodCall(obj, method, args, span) => {
+                let receiver = self.value(obj, scope);
+                let outer = std::mem::replace(&mut self.span, span.0.clone());
+                let kind = self.method_call(receiver, method, args, span, scope);
+                self.span = outer;
+                kind
+            }
+
+            Expr::Concatenate(left, right) => {
+                for operand in [left, right] {
+                    let kind = self.value(operand, scope);
+                    if !kind.fits(Kind::String) && !kind.fits(Kind::Number) {
+                        self.error(
+                            self.span_of(operand),
+                            format!("Expected string or number, found {}", kind.describe()),
+                        );
+                    }
+                }
+                Kind::String
+            }
+
+            Expr::Equal(left, right) | Expr::NotEqual(left, right) => {
+                let left_kind = self.value(left, scope);
+                let right_kind = self.value(right, scope);
+
+                let comparable = |kind: Kind| match kind {
+                    Kind::String | Kind::Range => Some(Kind::String),
+                    Kind::Bool => Some(Kind::Bool),
+                    Kind::Number => Some(Kind::Number),
+                    _ => None,
+                };
+                let known = left_kind != Kind::Unknown && right_kind != Kind::Unknown;
+                if known
+                    && (comparable(left_kind).is_none()
+                        || comparable(left_kind) != comparable(right_kind))
+                {
+                    self.error(
+                        self.span_of(left),
+                        format!(
+                            "Can't compare {} with {}",
+                            left_kind.describe(),
+                            right_kind.describe()
+                        ),
+                    );
+                }
+                Kind::Bool
+            }
+
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                self.expect(left, scope, Kind::Bool);
+                self.expect(right, scope, Kind::Bool);
+                Kind::Bool
+            }
+            Expr::Not(e) => {
+                self.expect(e, scope, Kind::Bool);
+                Kind::Bool
+            }
+
+            Expr::Binding(_, _) | Expr::Alternation(_, _) | Expr::Repeat(_, _) => {
+                self.error(
+                    self.span_of(expr),
+                    "Patterns can only be used as the argument of a find function".to_string(),
+                );
+                Kind::Unknown
+            }
+        }
+    }
+
+    fn fn_call(
+        &mut self,
+        func: &str,
+        args: &[Expr],
+        span: &Span,
+        scope: &HashMap<String, Kind>,
+    ) -> Kind {
+        match func {
+            "find" | "find_selected" | "find_in_file" => {
+                self.arity(func, args, span, 1..2);
+                if let Some(pattern) = args.first() {
+                    self.pattern(pattern, scope);
+                }
+                if func == "find_in_file" {
+                    Kind::Ranges
+                } else {
+                    Kind::Range
+                }
+            }
+
+            "input_string" => {
+                self.arity(func, args, span, 2..3);
+                for arg in args {
+                    if !matches!(arg, Expr::StringLiteral(_)) {
+                        self.error(
+                            self.span_of(arg),
+                            "Arguments to input_string must be string literals".to_string(),
+                        );
+                    }
+                }
+                Kind::String
+            }
+
+            "abort" => {
+                self.arity(func, args, span, 0..2);
+                for arg in args {
+                    self.expect(arg, scope, Kind::String);
+                }
+                Kind::Unknown
+            }
+
+            "select" | "cursor_at" => {
+                self.arity(func, args, span, 1..2);
+                if let Some(arg) = args.first() {
+                    let expected = if func == "select" {
+                        Kind::Range
+                    } else {
+                        Kind::Number
+                    };
+                    self.expect(arg, scope, expected);
+                }
+                Kind::Edits
+            }
+
+            "files" => {
+                self.arity(func, args, span, 1..2);
+                for arg in args {
+                    self.expect(arg, scope, Kind::String);
+                }
+                Kind::List
+            }
+
+            "balanced" | "node" => {
+                self.error(
+                    span.0.clone(),
+                    format!("{} can only be used in a pattern", func),
+                );
+                Kind::Unknown
+            }
+
+            _ => match scope.get(func) {
+                Some(Kind::Function(params)) => {
+                    self.arity(func, args, span, *params..*params + 1);
+                    for arg in args {
+                        self.value(arg, scope);
+                    }
+                    Kind::Unknown
+                }
+                Some(Kind::Unknown) => {
+                    for arg in args {
+                        self.value(arg, scope);
+                    }
+                    Kind::Unknown
+                }
+                _ => {
+                    self.error(span.0.clone(), format!("Unknown function {:?}", func));
+                    Kind::Unknown
+                }
+            },
+        }
+    }
+
+    fn method_call(
+        &mut self,
+        receiver: Kind,
+        method: &str,
+        args: &[Expr],
+        span: &Span,
+        scope: &HashMap<String, Kind>,
+    ) -> Kind {
+        let range_params = match method {
+            "delete" => Some(0),
+            "replace_with" | "insert_before" | "insert_after" => Some(1),
+            "wrap" => Some(2),
+            _ => None,
+        };
+        if let Some(params) = range_params {
+            if !receiver.fits(Kind::Range) {
+                self.error(
+                    span.0.clone(),
+                    format!("{} has no method {:?}", receiver.describe(), method),
+                );
+            }
+            self.arity(method, args, span, params..params + 1);
+            for arg in args {
+                self.expect(arg, scope, Kind::String);
+            }
+            return Kind::Edits;
+        }
+
+        // Files only come out of lists, so their kind is never known.
+        if method == "find_all" {
+            if receiver != Kind::Unknown {
+                self.error(
+                    span.0.clone(),
+                    format!("{} has no method \"find_all\"", receiver.describe()),
+                );
+            }
+            self.arity(method, args, span, 1..2);
+            if let Some(pattern) = args.first() {
+                self.pattern(pattern, scope);
+            }
+            return Kind::Ranges;
+        }
+
+        if method == "len" && matches!(receiver, Kind::List | Kind::Ranges | Kind::Map) {
+            self.arity(method, args, span, 0..1);
+            return Kind::Number;
+        }
+
+        // String methods and the arguments they take, with `Regex` standing for any pattern.
+        let (params, result): (&[Kind], Kind) = match method {
+            "to_upper" | "to_lower" | "to_snake_case" | "to_camel_case" | "to_pascal_case"
+            | "trim" => (&[], Kind::String),
+            "len" => (&[], Kind::Number),
+            "split" => (&[Kind::Regex], Kind::List),
+            "replace" => (&[Kind::Regex, Kind::String], Kind::String),
+            _ => {
+                self.error(span.0.clone(), format!("Unknown method {:?}", method));
+                return Kind::Unknown;
+            }
+        };
+
+        if !receiver.fits(Kind::String) {
+            self.error(
+                span.0.clone(),
+                format!("{} has no method {:?}", receiver.describe(), method),
+            );
+        }
+        self.arity(method, args, span, params.len()..params.len() + 1);
+        for (arg, param) in args.iter().zip(params) {
+            match param {
+                Kind::Regex => self.pattern(arg, scope),
+                kind => self.expect(arg, scope, *kind),
+            }
+        }
+        result
+    }
+
+    /// Checks an expression used as a pattern.
+    fn pattern(&mut self, expr: &Expr, scope: &HashMap<String, Kind>) {
+        match expr {
+            Expr::StringLiteral(_) | Expr::Regex(_) => {}
+
+            Expr::Ident(_, _) | Expr::DotAccess(_, _) => {
+                let kind = self.value(expr, scope);
+                if !(kind.fits(Kind::String) || kind == Kind::Regex) {
+                    self.error(
+                        self.span_of(expr),
+                        format!("Expected pattern, found {}", kind.describe()),
+                    );
+                }
+            }
+
+            Expr::Concatenate(left, right) | Expr::Alternation(left, right) => {
+                self.pattern(left, scope);
+                self.pattern(right, scope);
+            }
+            Expr::Repeat(e, _) | Expr::Binding(_, e) => self.pattern(e, scope),
+
+            Expr::FnCall(func, args, span) if func == "balanced" => {
+                self.arity(func, args, span, 1..3);
+                for arg in args {
+                    self.expect(arg, scope, Kind::String);
+                }
+
+                match args.as_slice() {
+                    [Expr::StringLiteral(open)]
+                        if !["(", "[", "{", "<"].contains(&open.as_str()) =>
+                    {
+                        self.error(
+                            span.0.clone(),
+                            format!("No default closing delimiter for {:?}", open),
+                        )
+                    }
+                    [Expr::StringLiteral(open), Expr::StringLiteral(close)]
+                        if open.is_empty() || close.is_empty() || open == close =>
+                    {
+                        self.error(
+                            span.0.clone(),
+                            format!("Invalid delimiters {:?} and {:?}", open, close),
+                        )
+                    }
+                    _ => {}
+                }
+            }
+
+            Expr::FnCall(func, args, span) if func == "node" => {
+                self.arity(func, args, span, 1..2);
+                match args.first() {
+                    Some(Expr::StringLiteral(kind)) if !syntax::is_kind(kind) => {
+                        self.error(span.0.clone(), format!("Unknown syntax node {:?}", kind))
+                    }
+                    Some(arg) => self.expect(arg, scope, Kind::String),
+                    None => {}
+                }
+            }
+
+            _ => {
+                let kind = self.value(expr, scope);
+                self.error(
+                    self.span_of(expr),
+                    format!("Expected pattern, found {}", kind.describe()),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refactorings::parser::parse;
+
+    fn errors(s: &str) -> Vec<String> {
+        check(&parse(s).unwrap())
+            .into_iter()
+            .map(|d| d.message)
+            .collect()
+    }
+
+    fn error_spans(s: &str) -> Vec<Range<usize>> {
+        check(&parse(s).unwrap())
+            .into_iter()
+            .map(|d| d.span)
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(
+            errors(
+                r#"import { ws } from "rust";
+                let with = input_string("with", "With");
+                let region = find(a:"a"? .. ws | balanced("(") .. b:/b/+);
+                if region.a == "a" && !(region.b != with) {
+                    abort("no");
+                }
+                for r in find_in_file(region.a) {
+                    r.replace_with("x" .. with);
+                }"#
+            ),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn unknown_variable() {
+        assert_eq!(errors(r#"find(foo);"#), vec![r#"Unknown variable "foo""#]);
+        assert_eq!(error_spans(r#"find(foo);"#), vec![5..8]);
+    }
+
+    #[test]
+    fn variables_scoped_to_blocks() {
+        assert_eq!(
+            errors(r#"for r in find_in_file("a") { let x = r; } find(x);"#),
+            vec![r#"Unknown variable "x""#]
+        );
+        assert_eq!(
+            errors(r#"let x = find(x);"#),
+            vec![r#"Unknown variable "x""#]
+        );
+    }
+
+    #[test]
+    fn unknown_function_and_method() {
+        assert_eq!(
+            errors(r#"fnd("a"); let r = find("a"); r.remove();"#),
+            vec![r#"Unknown function "fnd""#, r#"Unknown method "remove""#]
+        );
+        assert_eq!(
+            error_spans(r#"fnd("a"); let r = find("a"); r.remove();"#),
+            vec![0..8, 31..39]
+        );
+    }
+
+    #[test]
+    fn arity() {
+        assert_eq!(
+            errors(r#"find("a", "b"); input_string("a"); let r = find("a"); r.replace_with();"#),
+            vec![
+                "find takes 1 argument, found 2",
+                "input_string takes 2 arguments, found 1",
+                "replace_with takes 1 argument, found 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn argument_kinds() {
+        assert_eq!(
+            errors(
+                r#"for r in find("a") {}
+                if "a" {}
+                abort(true);
+                let x = find_in_file("a");
+                x.replace_with("b");"#
+            ),
+            vec![
+                "Expected list, found range",
+                "Expected bool, found string",
+                "Expected string, found bool",
+                "list of ranges has no method \"replace_with\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn string_methods() {
+        assert_eq!(
+            errors(
+                r#"let r = find("a");
+                let n = r.to_upper().trim().len() == 1;
+                for part in r.split("," | /;/) { let p = part; }
+                let s = "a".replace(/a/, "b");
+                let e = "a".replace("b");
+                let x = r.trim("b");
+                let y = n.to_lower();
+                for c in r.len() {}"#
+            ),
+            vec![
+                "replace takes 2 arguments, found 1",
+                "trim takes 0 arguments, found 1",
+                "bool has no method \"to_lower\"",
+                "Expected list, found number",
+            ]
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            errors(
+                r#"fn negate(e) { return "!(" .. e .. ")"; }
+                fn count(n) { if n == 0 { return n; } return count(n); }
+                let r = find("a");
+                r.replace_with(negate(r));
+                negate(r, r);
+                later();
+                fn later() {}
+                fn find(a) {}
+                fn scoped() { return r; }
+                return r;"#
+            ),
+            vec![
+                "negate takes 1 argument, found 2",
+                r#"Unknown function "later""#,
+                r#"Can't redefine builtin function "find""#,
+                "Can only return from a function",
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_and_maps() {
+        assert_eq!(
+            errors(
+                r#"let ops = {"<": ">=", ">": "<="};
+                let r = find(op:("<" | ">"));
+                r.op.replace_with(ops[r.op]);
+                let regions = find_in_file("a");
+                regions[0].replace_with(regions[0].to_upper() .. [1, 2].len());
+                let a = ops[0] .. regions["a"];
+                let b = r[0];
+                let c = {true: "a"};"#
+            ),
+            vec![
+                "Expected string, found number",
+                "Expected number, found string",
+                "Can't index range",
+                "Expected string, found bool",
+            ]
+        );
+    }
+
+    #[test]
+    fn range_methods() {
+        assert_eq!(
+            errors(
+                r#"let r = find("a");
+                r.delete();
+                r.insert_before("&");
+                r.insert_after(r.text .. r.line);
+                r.wrap("(", ")");
+                "a".delete();
+                r.wrap("(");"#
+            ),
+            vec![
+                "string has no method \"delete\"",
+                "wrap takes 2 arguments, found 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn selections() {
+        assert_eq!(
+            errors(
+                r#"let r = find("a");
+                select(r);
+                cursor_at(r.end);
+                select("a");
+                cursor_at(r);"#
+            ),
+            vec![
+                "Expected range, found string",
+                "Expected number, found range"
+            ]
+        );
+    }
+
+    #[test]
+    fn workspace_files() {
+        assert_eq!(
+            errors(
+                r#"for f in files("**/*.rs") {
+                    for r in f.find_all("a" .. b:/\w/) { r.b.replace_with(f.path); }
+                }
+                files(1);
+                "a".find_all("a");"#
+            ),
+            vec![
+                "Expected string, found number",
+                "string has no method \"find_all\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn input_string_literals() {
+        assert_eq!(
+            errors(r#"let key = "a"; input_string(key, "Prompt");"#),
+            vec!["Arguments to input_string must be string literals"]
+        );
+    }
+
+    #[test]
+    fn operand_kinds() {
+        assert_eq!(
+            errors(r#"let a = "a" == true; let b = !"b"; let c = "c" .. true;"#),
+            vec![
+                "Can't compare string with bool",
+                "Expected bool, found string",
+                "Expected string or number, found bool",
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_only_in_find() {
+        assert_eq!(
+            errors(r#"let a = x:"a"; let b = balanced("("); find(true);"#),
+            vec![
+                "Patterns can only be used as the argument of a find function",
+                "balanced can only be used in a pattern",
+                "Expected pattern, found bool",
+            ]
+        );
+    }
+
+    #[test]
+    fn balanced_delimiters() {
+        assert_eq!(
+            errors(r#"find(balanced("|")); find(balanced("|", "|"));"#),
+            vec![
+                r#"No default closing delimiter for "|""#,
+                r#"Invalid delimiters "|" and "|""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_nodes() {
+        assert_eq!(
+            errors(
+                r#"find(node("binary_expression") | node("binary_expr")); node("_expression");"#
+            ),
+            vec![
+                r#"Unknown syntax node "binary_expr""#,
+                "node can only be used in a pattern",
+            ]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let script = parse(r#"@id = "a"; @nmae = "b";"#).unwrap();
+
+        assert_eq!(
+            check_directives(&script)
+                .into_iter()
+                .map(|d| d.message)
+                .collect::<Vec<_>>(),
+            vec!["Unknown directive @nmae", "Missing @name directive"]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::fmt;
+
+/// Why running a script did not produce edits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KybError {
+    /// A pattern has no match where the script looked for one.
+    NotFound,
+    /// A binding that was not part of the match, like an optional one that matched nothing.
+    Unbound(String),
+    /// The script called `abort`.
+    Aborted(String),
+    /// An index past the end of a list, like the first of a repetition that matched nothing.
+    OutOfBounds {
+        index: usize,
+        len: usize,
+    },
+    MissingKey(String),
+    MissingInput(String),
+
+    Type {
+        expected: &'static str,
+        found: &'static str,
+    },
+    UnknownVariable(String),
+    UnknownFunction(String),
+    UnknownMethod {
+        receiver: &'static str,
+        method: String,
+    },
+    UnknownProperty {
+        receiver: &'static str,
+        property: String,
+    },
+    BadArguments(String),
+    PatternOutsideFind,
+    ReturnOutsideFunction,
+    /// Too many calls in progress at once, starting with a call to this function.
+    RecursionLimit(String),
+    UnresolvedImport(String),
+    /// The script, or a module it imports, could not be loaded.
+    Load(String),
+    OverlappingEdits,
+    /// Keystrokes were asked for, but the script edits files other than the buffer.
+    EditsToOtherFiles,
+    /// The script looked for files, but the editor didn't say which workspace the buffer is in.
+    NoWorkspace,
+    /// Files of the workspace couldn't be read.
+    Workspace(String),
+    /// A `node` pattern was used on text of a language without a bundled grammar, or of no
+    /// known language.
+    NoGrammar(Option<String>),
+}
+
+impl KybError {
+    /// Whether the script itself is wrong, rather than just not applying to the editor contents.
+    pub fn is_bug(&self) -> bool {
+        !matches!(
+            self,
+            KybError::NotFound
+                | KybError::Unbound(_)
+                | KybError::Aborted(_)
+                | KybError::OutOfBounds { .. }
+                | KybError::MissingKey(_)
+                | KybError::NoWorkspace
+                | KybError::Workspace(_)
+                | KybError::NoGrammar(_)
+        )
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            KybError::NotFound => "not_found",
+            KybError::Unbound(_) => "unbound",
+            KybError::Aborted(_) => "aborted",
+            KybError::OutOfBounds { .. } => "out_of_bounds",
+            KybError::MissingKey(_) => "missing_key",
+            KybError::MissingInput(_) => "missing_input",
+            KybError::Type { .. } => "type",
+            KybError::UnknownVariable(_) => "unknown_variable",
+            KybError::UnknownFunction(_) => "unknown_function",
+            KybError::UnknownMethod { .. } => "unknown_method",
+            KybError::UnknownProperty { .. } => "unknown_property",
+            KybError::BadArguments(_) => "bad_arguments",
+            KybError::PatternOutsideFind => "pattern_outside_find",
+            KybError::ReturnOutsideFunction => "return_outside_function",
+            KybError::RecursionLimit(_) => "recursion_limit",
+            KybError::UnresolvedImport(_) => "unresolved_import",
+            KybError::Load(_) => "load",
+            KybError::OverlappingEdits => "overlapping_edits",
+            KybError::EditsToOtherFiles => "edits_to_other_files",
+            KybError::NoWorkspace => "no_workspace",
+            KybError::Workspace(_) => "workspace",
+            KybError::NoGrammar(_) => "no_grammar",
+        }
+    }
+}
+
+impl fmt::Display for KybError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KybError::NotFound => write!(f, "Not found"),
+            KybError::Unbound(name) => write!(f, "Region does not have binding {:?}", name),
+            KybError::Aborted(reason) => write!(f, "Aborted: {}", reason),
+            KybError::OutOfBounds { index, len } => {
+                write!(f, "Index {} is out of bounds for a list of {}", index, len)
+            }
+            KybError::MissingKey(key) => write!(f, "Map does not have key {:?}", key),
+            KybError::MissingInput(key) => write!(f, "Missing input {:?}", key),
+            KybError::Type { expected, found } => {
+                write!(f, "Expected {}, found {}", expected, found)
+            }
+            KybError::UnknownVariable(name) => write!(f, "Unknown variable {:?}", name),
+            KybError::UnknownFunction(name) => write!(f, "Unknown function {:?}", name),
+            KybError::UnknownMethod { receiver, method } => {
+                write!(f, "{} has no method {:?}", receiver, method)
+            }
+            KybError::UnknownProperty { receiver, property } => {
+                write!(f, "{} has no property {:?}", receiver, property)
+            }
+            KybError::BadArguments(message) => write!(f, "{}", message),
+            KybError::PatternOutsideFind => write!(
+                f,
+                "Patterns can only be used as the argument of a find function"
+            ),
+            KybError::ReturnOutsideFunction => write!(f, "Can only return from a function"),
+            KybError::RecursionLimit(name) => {
+                write!(f, "Too many nested calls, calling {:?}", name)
+            }
+            KybError::UnresolvedImport(source) => write!(f, "Unresolved import {:?}", source),
+            KybError::Load(message) => write!(f, "{}", message),
+            KybError::OverlappingEdits => write!(f, "Edits overlap"),
+            KybError::EditsToOtherFiles => {
+                write!(f, "Edits to other files can only be made as text edits")
+            }
+            KybError::NoWorkspace => write!(f, "No workspace to find files in"),
+            KybError::Workspace(message) => {
+                write!(f, "Could not read workspace files: {}", message)
+            }
+            KybError::NoGrammar(Some(language)) => {
+                write!(f, "No syntax nodes for language {:?}", language)
+            }
+            KybError::NoGrammar(None) => write!(f, "No syntax nodes for text of unknown language"),
+        }
+    }
+}
+
+/// Serialized as its kind and message, like `{"kind": "not_found", "message": "Not found"}`.
+impl Serialize for KybError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("KybError", 2)?;
+        s.serialize_field("kind", self.kind())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.end()
+    }
+}
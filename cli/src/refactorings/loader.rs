@@ -0,0 +1,391 @@
+use super::{
+    checker::{check, check_directives},
+    parser::{parse, render_all, Diagnostic},
+    script::Script,
+};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Parses scripts and resolves their imports.
+///
+/// `import { a } from "name";` is looked up as `name.kyb` in each directory of `KYBER_PATH`, then
+/// in the library bundled with kyber.
+pub struct Loader {
+    library: HashMap<String, String>,
+    search_path: Vec<PathBuf>,
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        let search_path = std::env::var_os("KYBER_PATH")
+            .map(|paths| std::env::split_paths(&paths).collect())
+            .unwrap_or_default();
+        Self::with_search_path(search_path)
+    }
+
+    /// A loader looking in `search_path` rather than `KYBER_PATH`.
+    pub fn with_search_path(search_path: Vec<PathBuf>) -> Self {
+        let library = [("rust", include_str!("./lib/rust.kyb"))]
+            .into_iter()
+            .map(|(name, source)| (name.to_string(), source.to_string()))
+            .collect();
+
+        Loader {
+            library,
+            search_path,
+        }
+    }
+
+    /// Loads the refactoring `contents`, naming it `file` in errors.
+    pub fn load(&self, file: &str, contents: &str) -> Result<Script, String> {
+        let script = self.load_with_stack(file, contents, &mut Vec::new())?;
+
+        let errors = check_directives(&script);
+        if !errors.is_empty() {
+            return Err(render_all(&errors, file, contents));
+        }
+        Ok(script)
+    }
+
+    fn load_with_stack(
+        &self,
+        file: &str,
+        contents: &str,
+        stack: &mut Vec<String>,
+    ) -> Result<Script, String> {
+        let mut script = parse(contents).map_err(|e| render_all(&e, file, contents))?;
+
+        let imports = script.imports().cloned().collect::<Vec<_>>();
+        for import in imports {
+            let error = |message| {
+                let diagnostic = Diagnostic::new(import.span.0.clone(), message);
+                render_all(&[diagnostic], file, contents)
+            };
+
+            if stack.contains(&import.source) {
+                stack.push(import.source.clone());
+                return Err(error(format!("Import cycle: {}", stack.join(" -> "))));
+            }
+
+            let (module_file, module_contents) =
+                self.module_contents(&import.source).map_err(error)?;
+            stack.push(import.source.clone());
+            let module = self
+                .load_with_stack(&module_file, &module_contents, stack)
+                .map_err(|e| format!("In {:?}: {}", import.source, e))?;
+            stack.pop();
+
+            if let Some(missing) = import.idents.iter().find(|i| !module.defines(i)) {
+                return Err(error(format!(
+                    "{:?} is not defined in {:?}",
+                    missing, import.source
+                )));
+            }
+            script.add_module(&import.source, module);
+        }
+
+        let errors = check(&script);
+        if !errors.is_empty() {
+            return Err(render_all(&errors, file, contents));
+        }
+        Ok(script)
+    }
+
+    /// The file name and contents of the module `name`.
+    fn module_contents(&self, name: &str) -> Result<(String, String), String> {
+        // Only names of files right in a search directory, so imports can't read anywhere else.
+        if matches!(name, "" | "." | "..") || name.contains(['/', '\\', ':']) {
+            return Err(format!("Invalid module name {:?}", name));
+        }
+
+        let file = format!("{}.kyb", name);
+        for dir in &self.search_path {
+            let path = dir.join(&file);
+            if path.is_file() {
+                return std::fs::read_to_string(&path)
+                    .map(|contents| (path.display().to_string(), contents))
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e));
+            }
+        }
+
+        self.library
+            .get(name)
+            .map(|contents| (file, contents.clone()))
+            .ok_or_else(|| format!("Could not find module {:?}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        refactorings::{Input, InputType, Mutation, Refactoring},
+        ContentRegion, EditorContext, PositionEncoding,
+    };
+
+    fn context(text: &str) -> EditorContext {
+        EditorContext {
+            contents: vec![ContentRegion {
+                text: text.to_string(),
+                selected: false,
+            }],
+            language_id: None,
+            file_path: None,
+            workspace_root: None,
+            position_encoding: PositionEncoding::Utf8,
+        }
+    }
+
+    /// `body` with the directives every refactoring needs.
+    fn refactoring(body: &str) -> String {
+        format!("@id = \"test\";\n@name = \"Test\";\n{}", body)
+    }
+
+    fn loader(modules: &[(&str, &str)]) -> Loader {
+        Loader {
+            library: modules
+                .iter()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect(),
+            search_path: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn no_imports() {
+        loader(&[])
+            .load("test.kyb", &refactoring(r#"find("t");"#))
+            .unwrap();
+    }
+
+    #[test]
+    fn named_import() {
+        let loader = loader(&[("common", r#"let foo = "t"; let bar = "u";"#)]);
+
+        loader
+            .load(
+                "test.kyb",
+                &refactoring(r#"import { foo, bar } from "common"; find(foo);"#),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn only_named_bindings_in_scope() {
+        let loader = loader(&[("common", r#"let foo = "t"; let bar = "u";"#)]);
+
+        let script = loader
+            .load(
+                "test.kyb",
+                &refactoring(r#"import { foo } from "common"; find(foo);"#),
+            )
+            .unwrap();
+        assert!(script.applies_to(&context("t")).unwrap());
+        assert!(!script.applies_to(&context("u")).unwrap());
+
+        let err = loader
+            .load(
+                "test.kyb",
+                &refactoring(r#"import { foo } from "common"; find(bar);"#),
+            )
+            .unwrap_err();
+        assert!(
+            err.starts_with(r#"error: Unknown variable "bar""#),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn imported_function() {
+        let loader = loader(&[(
+            "common",
+            r#"let suffix = "!"; fn shout(s) { return s.to_upper() .. suffix; }"#,
+        )]);
+
+        let script = loader
+            .load(
+                "test.kyb",
+                &refactoring(
+                    r#"import { shout } from "common"; let r = find("a"); r.replace_with(shout(r));"#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            script.perform(&context("a"), &HashMap::new()),
+            Ok(vec![
+                Mutation::Delete(1),
+                Mutation::Insert("A!".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn imported_inputs() {
+        let loader = loader(&[(
+            "common",
+            r#"fn replacement() { return input_string("with", "Replace with"); }"#,
+        )]);
+
+        let script = loader
+            .load(
+                "test.kyb",
+                &refactoring(
+                    r#"import { replacement } from "common"; find("a").replace_with(replacement());"#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            script.inputs(),
+            vec![Input {
+                key: "with".to_string(),
+                prompt: "Replace with".to_string(),
+                type_: InputType::String,
+            }]
+        );
+        assert_eq!(
+            script.perform(
+                &context("a"),
+                &[("with".to_string(), "b".to_string())]
+                    .into_iter()
+                    .collect()
+            ),
+            Ok(vec![Mutation::Delete(1), Mutation::Insert("b".to_string())])
+        );
+    }
+
+    #[test]
+    fn missing_module() {
+        let err = loader(&[])
+            .load("test.kyb", &refactoring(r#"import { foo } from "common";"#))
+            .unwrap_err();
+
+        assert_eq!(
+            err,
+            [
+                r#"error: Could not find module "common""#,
+                " --> test.kyb:3:1",
+                "  |",
+                r#"3 | import { foo } from "common";"#,
+                "  | ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn module_names_are_not_paths() {
+        let dir = std::env::temp_dir().join(format!("kyber-loader-paths-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("outside.kyb"), r#"let foo = "outside";"#).unwrap();
+
+        let mut loader = loader(&[]);
+        loader.search_path.push(dir.join("lib"));
+        for source in [
+            "../outside",
+            &dir.join("outside").display().to_string(),
+            "..",
+            "",
+        ] {
+            let import = format!("import {{ foo }} from {:?};", source);
+            let err = loader.load("test.kyb", &refactoring(&import)).unwrap_err();
+            assert!(
+                err.starts_with(&format!("error: Invalid module name {:?}", source)),
+                "{}",
+                err
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_name() {
+        let loader = loader(&[("common", r#"let foo = "t";"#)]);
+
+        let err = loader
+            .load("test.kyb", &refactoring(r#"import { bar } from "common";"#))
+            .unwrap_err();
+
+        assert!(
+            err.starts_with("error: \"bar\" is not defined in \"common\"\n --> test.kyb:3:1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let loader = loader(&[
+            ("a", r#"import { b } from "b"; let a = "a";"#),
+            ("b", r#"import { a } from "a"; let b = "b";"#),
+        ]);
+
+        let err = loader
+            .load("test.kyb", &refactoring(r#"import { a } from "a";"#))
+            .unwrap_err();
+
+        assert!(
+            err.starts_with("In \"a\": In \"b\": error: Import cycle: a -> b -> a\n --> b.kyb:1:1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn search_path() {
+        let dir = std::env::temp_dir().join(format!("kyber-loader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("common.kyb"), r#"let foo = "user";"#).unwrap();
+
+        let mut loader = loader(&[("common", r#"let bar = "bundled";"#)]);
+        loader.search_path.push(dir.clone());
+        let result = loader.load("test.kyb", &refactoring(r#"import { foo } from "common";"#));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn parse_errors_name_module() {
+        let loader = loader(&[("common", "let foo = ;")]);
+
+        let err = loader
+            .load("test.kyb", &refactoring(r#"import { foo } from "common";"#))
+            .unwrap_err();
+
+        assert!(
+            err.starts_with("In \"common\": error: Expected expr"),
+            "{}",
+            err
+        );
+        assert!(err.contains("--> common.kyb:1:11"), "{}", err);
+    }
+
+    #[test]
+    fn semantic_errors() {
+        let err = loader(&[])
+            .load(
+                "test.kyb",
+                &refactoring(r#"let r = find("a"); r.remove();"#),
+            )
+            .unwrap_err();
+
+        assert!(err.contains("--> test.kyb:3:22"), "{}", err);
+    }
+
+    #[test]
+    fn missing_directives() {
+        let err = loader(&[])
+            .load("test.kyb", r#"@id = "test"; find("a");"#)
+            .unwrap_err();
+
+        assert!(err.starts_with("error: Missing @name directive"), "{}", err);
+    }
+
+    #[test]
+    fn bundled_library() {
+        Loader::new()
+            .load("test.kyb", &refactoring(r#"import { ident } from "rust";"#))
+            .unwrap();
+    }
+}
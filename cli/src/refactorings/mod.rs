@@ -1,29 +1,107 @@
 use crate::EditorContext;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
+mod case;
+mod checker;
+mod error;
+mod loader;
 mod parser;
+mod pattern;
 mod script;
+mod syntax;
+mod workspace;
+
+pub use error::KybError;
+pub use syntax::language_of_path;
+pub use workspace::{root_of, write_edits};
 
 pub trait Refactoring {
-    fn applies_to(&self, context: &EditorContext) -> bool;
-    fn perform(&self, context: &EditorContext) -> Result<Vec<Mutation>, String>;
+    /// Whether the refactoring is meant for the language and file of `context`, which is cheap
+    /// to check before running it.
+    fn available_in(&self, context: &EditorContext) -> bool;
+    /// Whether the refactoring can be performed, or the bug in it that stopped it from running.
+    fn applies_to(&self, context: &EditorContext) -> Result<bool, KybError>;
+    fn perform(
+        &self,
+        context: &EditorContext,
+        inputs: &HashMap<String, String>,
+    ) -> Result<Vec<Mutation>, PerformError>;
+    /// Like `perform`, but as edits to ranges of the buffer rather than keystrokes.
+    fn perform_edits(
+        &self,
+        context: &EditorContext,
+        inputs: &HashMap<String, String>,
+        coordinates: Coordinates,
+    ) -> Result<TextEdits, PerformError>;
+    fn inputs(&self) -> Vec<Input>;
 
     fn id(&self) -> String;
     fn name(&self) -> String;
     fn description(&self) -> String;
 }
 
-pub fn all() -> impl Iterator<Item = Box<dyn Refactoring>> {
+/// A bundled refactoring that could not be loaded, like one importing a broken module from
+/// `KYBER_PATH`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LoadError {
+    pub file: &'static str,
+    /// The diagnostics, rendered.
+    pub message: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not load {}: {}", self.file, self.message)
+    }
+}
+
+pub fn all() -> impl Iterator<Item = Result<Box<dyn Refactoring>, LoadError>> {
+    load_all(loader::Loader::new())
+}
+
+fn load_all(
+    loader: loader::Loader,
+) -> impl Iterator<Item = Result<Box<dyn Refactoring>, LoadError>> {
     [
-        include_str!("./rust/extract_not_eq.kyb"),
-        include_str!("./rust/replace_eq_false.kyb"),
-        include_str!("./rust/remove_surrounding_parens.kyb"),
-        include_str!("./rust/remove_double_not.kyb"),
-        include_str!("./rust/rename_symbol.kyb"),
+        (
+            "rust/extract_not_eq.kyb",
+            include_str!("./rust/extract_not_eq.kyb"),
+        ),
+        (
+            "rust/replace_eq_false.kyb",
+            include_str!("./rust/replace_eq_false.kyb"),
+        ),
+        (
+            "rust/remove_surrounding_parens.kyb",
+            include_str!("./rust/remove_surrounding_parens.kyb"),
+        ),
+        (
+            "rust/remove_double_not.kyb",
+            include_str!("./rust/remove_double_not.kyb"),
+        ),
+        (
+            "rust/rename_symbol.kyb",
+            include_str!("./rust/rename_symbol.kyb"),
+        ),
+        (
+            "rust/rename_symbol_in_workspace.kyb",
+            include_str!("./rust/rename_symbol_in_workspace.kyb"),
+        ),
+        (
+            "rust/invert_comparison.kyb",
+            include_str!("./rust/invert_comparison.kyb"),
+        ),
     ]
     .into_iter()
-    .map(|s| Box::new(parser::parse(s).unwrap()) as Box<dyn Refactoring>)
+    .map(move |(file, s)| match loader.load(file, s) {
+        Ok(script) => Ok(Box::new(script) as Box<dyn Refactoring>),
+        Err(message) => Err(LoadError { file, message }),
+    })
 }
 
 #[derive(Serialize, Debug, PartialEq, Eq, Clone)]
@@ -32,4 +110,325 @@ pub enum Mutation {
     Delete(usize),
     Backspace(usize),
     Insert(String),
+    /// Selects from `start` to `end`, as offsets into the buffer after the other mutations.
+    Select {
+        start: usize,
+        end: usize,
+    },
+    /// Moves the cursor to an offset into the buffer after the other mutations.
+    Cursor(usize),
+}
+
+/// How positions in [`TextEdits`] are given, counted in the context's position encoding.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Coordinates {
+    /// Offsets into the buffer.
+    #[default]
+    Offset,
+    /// Lines and columns, both counted from 0.
+    LineColumn,
+}
+
+/// Edits that make all of a refactoring's changes at once, wherever the cursor is.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TextEdits {
+    pub edits: Vec<TextEdit>,
+    /// What to select once the edits are made, in the edited buffer.
+    pub selection: Option<TextRange>,
+    /// Edits to other files of the workspace, by path.
+    pub files: BTreeMap<String, Vec<TextEdit>>,
+}
+
+/// Replacement of a range of the buffer as it was before any of the edits.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct TextEdit {
+    pub range: TextRange,
+    pub new_text: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TextRange {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(untagged)]
+pub enum Position {
+    Offset(usize),
+    LineColumn { line: usize, column: usize },
+}
+
+/// A value the user has to provide before a refactoring can be performed.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Input {
+    pub key: String,
+    pub prompt: String,
+    #[serde(rename = "type")]
+    pub type_: InputType,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum InputType {
+    String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PerformError {
+    MissingInput(Input),
+    Failed(KybError),
+}
+
+impl From<KybError> for PerformError {
+    fn from(e: KybError) -> Self {
+        PerformError::Failed(e)
+    }
+}
+
+impl fmt::Display for PerformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PerformError::MissingInput(input) => {
+                write!(f, "Missing input {:?} ({})", input.key, input.prompt)
+            }
+            PerformError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PerformError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ContentRegion, PositionEncoding};
+
+    fn refactoring(id: &str) -> Box<dyn Refactoring> {
+        all().map(Result::unwrap).find(|r| r.id() == id).unwrap()
+    }
+
+    fn context(regions: &[&str]) -> EditorContext {
+        EditorContext {
+            contents: regions
+                .iter()
+                .enumerate()
+                .map(|(i, text)| ContentRegion {
+                    text: text.to_string(),
+                    selected: i % 2 == 1,
+                })
+                .collect(),
+            language_id: Some("rust".to_string()),
+            file_path: None,
+            workspace_root: None,
+            position_encoding: PositionEncoding::Utf8,
+        }
+    }
+
+    #[test]
+    fn broken_module_on_search_path() {
+        let root = workspace::tests::workspace("broken_module", &[("rust.kyb", "let ident = ;")]);
+        let loaded = load_all(loader::Loader::with_search_path(vec![root]))
+            .map(|r| r.map(|r| r.id()).map_err(|e| e.file))
+            .collect::<Vec<_>>();
+
+        assert!(loaded.contains(&Err("rust/rename_symbol.kyb")));
+        assert!(loaded.contains(&Ok("remove_surrounding_parens".to_string())));
+    }
+
+    #[test]
+    fn extract_not_eq() {
+        let extract = refactoring("extract_not_eq");
+
+        assert_eq!(
+            extract
+                .perform(&context(&["a ", "", "!= b"]), &HashMap::new())
+                .unwrap(),
+            vec![
+                Mutation::Delete(4),
+                Mutation::Backspace(2),
+                Mutation::Insert("!(a == b)".to_string())
+            ]
+        );
+        assert_eq!(
+            extract
+                .perform(&context(&["f(x) ", "", "!= a.b;"]), &HashMap::new())
+                .unwrap(),
+            vec![
+                Mutation::Delete(6),
+                Mutation::Backspace(5),
+                Mutation::Insert("!(f(x) == a.b)".to_string())
+            ]
+        );
+        assert!(!extract
+            .applies_to(&context(&["a ", "", "!= false"]))
+            .unwrap());
+    }
+
+    #[test]
+    fn only_available_in_rust() {
+        let mut context = context(&["a ", "", "!= b"]);
+        context.language_id = Some("python".to_string());
+        assert_eq!(
+            all()
+                .map(Result::unwrap)
+                .filter(|r| r.available_in(&context))
+                .count(),
+            0
+        );
+
+        context.language_id = Some("rust".to_string());
+        context.file_path = Some("src/main.rs".to_string());
+        assert_eq!(
+            all()
+                .map(Result::unwrap)
+                .filter(|r| r.available_in(&context))
+                .count(),
+            all().count()
+        );
+    }
+
+    #[test]
+    fn remove_surrounding_parens() {
+        let remove = refactoring("remove_surrounding_parens");
+
+        assert_eq!(
+            remove
+                .perform(&context(&["x = ((a", "", ") + b);"]), &HashMap::new())
+                .unwrap(),
+            vec![
+                Mutation::Delete(6),
+                Mutation::Backspace(3),
+                Mutation::Insert("(a) + b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn invert_comparison() {
+        let invert = refactoring("invert_comparison");
+
+        assert_eq!(
+            invert
+                .perform(&context(&["a <", "", "= b"]), &HashMap::new())
+                .unwrap(),
+            vec![
+                Mutation::Delete(1),
+                Mutation::Backspace(1),
+                Mutation::Insert(">".to_string())
+            ]
+        );
+        assert!(!invert.applies_to(&context(&["a + ", "", "b"])).unwrap());
+    }
+
+    #[test]
+    fn rename_symbol() {
+        let rename = refactoring("rename_symbol");
+        let context = context(&["let f", "", "oo = foo + 1;"]);
+        let inputs = [("replace_with".to_string(), "bar".to_string())]
+            .into_iter()
+            .collect();
+
+        assert!(rename.applies_to(&context).unwrap());
+        assert_eq!(
+            rename.perform(&context, &inputs).unwrap(),
+            vec![
+                Mutation::Delete(8),
+                Mutation::Backspace(1),
+                Mutation::Insert("bar = bar".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_symbol_in_workspace() {
+        let rename = refactoring("rename_symbol_in_workspace");
+        let root = workspace::tests::workspace(
+            "rename_symbol_in_workspace",
+            &[
+                ("src/main.rs", ""),
+                ("src/lib.rs", "use foo; foobar();"),
+                ("src/a.rs", "foo(foo,foo)"),
+            ],
+        );
+        let mut context = context(&["let f", "", "oo = 1;"]);
+        context.file_path = Some(root.join("src/main.rs").display().to_string());
+        let inputs = [("replace_with".to_string(), "bar".to_string())]
+            .into_iter()
+            .collect();
+
+        assert!(!rename.applies_to(&context).unwrap());
+
+        context.workspace_root = Some(root.display().to_string());
+        assert!(rename.applies_to(&context).unwrap());
+        let edits = rename
+            .perform_edits(&context, &inputs, Coordinates::Offset)
+            .unwrap();
+        let edit = |start, end| TextEdit {
+            range: TextRange {
+                start: Position::Offset(start),
+                end: Position::Offset(end),
+            },
+            new_text: "bar".to_string(),
+        };
+        assert_eq!(edits.edits, vec![edit(4, 7)]);
+        assert_eq!(
+            edits.files,
+            BTreeMap::from([
+                (
+                    root.join("src/a.rs").display().to_string(),
+                    vec![edit(0, 3), edit(4, 7), edit(8, 11)]
+                ),
+                (
+                    root.join("src/lib.rs").display().to_string(),
+                    vec![edit(4, 7)]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn rename_symbol_boundaries() {
+        let rename = refactoring("rename_symbol");
+        let inputs = [("replace_with".to_string(), "bar".to_string())]
+            .into_iter()
+            .collect();
+        let renamed = |regions: &[&str]| {
+            rename
+                .perform_edits(&context(regions), &inputs, Coordinates::Offset)
+                .unwrap()
+                .edits
+                .into_iter()
+                .map(|e| match e.range.start {
+                    Position::Offset(start) => start,
+                    Position::LineColumn { .. } => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            renamed(&["let ", "foo", " = 1; g(foo,foo); foo"]),
+            vec![4, 15, 19, 25]
+        );
+        assert_eq!(renamed(&["", "foo", " = foobar;"]), vec![0]);
+        assert_eq!(renamed(&["x = foo; ", "foo", ""]), vec![4, 9]);
+    }
+
+    #[test]
+    fn rename_symbol_inputs() {
+        let rename = refactoring("rename_symbol");
+        let input = Input {
+            key: "replace_with".to_string(),
+            prompt: "Replace with".to_string(),
+            type_: InputType::String,
+        };
+
+        assert_eq!(rename.inputs(), vec![input.clone()]);
+        assert_eq!(
+            rename.perform(&context(&["let f", "", "oo = foo + 1;"]), &HashMap::new()),
+            Err(PerformError::MissingInput(input))
+        );
+    }
 }
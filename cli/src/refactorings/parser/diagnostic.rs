@@ -0,0 +1,107 @@
+use std::ops::Range;
+
+/// An error at a span of a script's source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(span: Range<usize>, message: String) -> Self {
+        Diagnostic { span, message }
+    }
+
+    /// The error with the file, line and column it is at and the source line marked below.
+    ///
+    /// ```text
+    /// error: Expected SemiColon, found Ident("b")
+    ///  --> rust/example.kyb:1:13
+    ///   |
+    /// 1 | let a = "a" b;
+    ///   |             ^
+    /// ```
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+
+        let line = source[..start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count() + 1;
+        let marked = source[start..self.span.end.clamp(start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        format!(
+            "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.message,
+            gutter,
+            file,
+            line,
+            column,
+            gutter,
+            number,
+            &source[line_start..line_end],
+            gutter,
+            " ".repeat(column - 1),
+            "^".repeat(marked),
+        )
+    }
+}
+
+/// Every diagnostic rendered, separated by blank lines.
+pub fn render_all(diagnostics: &[Diagnostic], file: &str, source: &str) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.render(file, source))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_span() {
+        let source = "let a = \"a\";\nlet b = \"b\" c;\n";
+        let diagnostic = Diagnostic::new(25..26, "Expected SemiColon".to_string());
+
+        assert_eq!(
+            diagnostic.render("test.kyb", source),
+            [
+                "error: Expected SemiColon",
+                " --> test.kyb:2:13",
+                "  |",
+                "2 | let b = \"b\" c;",
+                "  |             ^",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn end_of_file() {
+        let source = "find(\"a\"";
+        let diagnostic = Diagnostic::new(8..8, "Expected CloseParen, found EOF".to_string());
+
+        assert!(diagnostic
+            .render("test.kyb", source)
+            .ends_with("1 | find(\"a\"\n  |         ^"));
+    }
+
+    #[test]
+    fn counts_chars() {
+        let source = "let é = ✓;";
+        let diagnostic = Diagnostic::new(9..12, "Unexpected \"✓\"".to_string());
+
+        assert!(diagnostic
+            .render("test.kyb", source)
+            .ends_with("1 | let é = ✓;\n  |         ^"));
+    }
+}
@@ -1,23 +1,138 @@
+use super::{Diagnostic, Span};
 use logos::*;
-use std::collections::*;
+use std::{collections::*, ops::Range};
 
-pub type Tokens = VecDeque<Token>;
+pub fn lex(contents: &str) -> Result<Tokens, Vec<Diagnostic>> {
+    let mut tokens = VecDeque::new();
+    let mut errors = Vec::new();
 
-pub fn lex(contents: &str) -> Result<Tokens, String> {
-    let mut tokens = Token::lexer(contents);
-    std::iter::from_fn(|| {
-        Some(match tokens.next()? {
-            Token::Error => Err(tokens.slice().to_owned()),
-            t => Ok(t),
-        })
+    let mut lexer = Token::lexer(contents);
+    while let Some(token) = lexer.next() {
+        match token {
+            Token::Error => errors.push(Diagnostic::new(
+                lexer.span(),
+                format!("Unexpected {:?}", lexer.slice()),
+            )),
+            t => tokens.push_back((t, lexer.span())),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Tokens {
+        tokens,
+        last: None,
+        last_span: 0..0,
+        end: contents.len(),
+        depth: 0,
     })
-    .collect()
+}
+
+/// Tokens left to parse, with the span of the source each came from.
+#[derive(Debug)]
+pub struct Tokens {
+    tokens: VecDeque<(Token, Range<usize>)>,
+    /// Token most recently taken, if it hasn't been put back.
+    last: Option<Token>,
+    /// Span of the token most recently taken, or of the end of the source if there were none left.
+    last_span: Range<usize>,
+    end: usize,
+    /// Braces opened and not yet closed by the tokens taken so far.
+    depth: usize,
+}
+
+impl Tokens {
+    pub fn pop_front(&mut self) -> Option<Token> {
+        let (token, span) = match self.tokens.pop_front() {
+            Some(next) => next,
+            None => {
+                self.last = None;
+                self.last_span = self.end..self.end;
+                return None;
+            }
+        };
+
+        match token {
+            Token::OpenBrace => self.depth += 1,
+            Token::CloseBrace => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+        self.last = Some(token.clone());
+        self.last_span = span;
+        Some(token)
+    }
+
+    /// Puts back the token most recently taken.
+    pub fn push_front(&mut self, token: Token) {
+        match token {
+            Token::OpenBrace => self.depth -= 1,
+            Token::CloseBrace => self.depth += 1,
+            _ => {}
+        }
+        self.last = None;
+        self.tokens.push_front((token, self.last_span.clone()));
+    }
+
+    pub fn front(&self) -> Option<&Token> {
+        self.get(0)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Token> {
+        self.tokens.get(index).map(|(t, _)| t)
+    }
+
+    /// Span of the next token, or of the end of the source if there are none left.
+    pub fn next_span(&self) -> Range<usize> {
+        self.tokens
+            .front()
+            .map_or(self.end..self.end, |(_, span)| span.clone())
+    }
+
+    pub fn last_span(&self) -> Range<usize> {
+        self.last_span.clone()
+    }
+
+    /// Span from `start` to the end of the token most recently taken.
+    pub fn span_from(&self, start: usize) -> Span {
+        Span(start..self.last_span.end)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Error about the token most recently taken.
+    pub fn error(&self, message: String) -> Diagnostic {
+        Diagnostic::new(self.last_span.clone(), message)
+    }
+
+    /// Skips the rest of the top level statement the parser stopped in.
+    ///
+    /// If the parser stopped at the start of the next statement, that statement is kept.
+    pub fn recover(&mut self) {
+        if self.depth == 0 {
+            if let Some(Token::Import | Token::Directive | Token::Fn | Token::Let) = self.last {
+                let token = self.last.take().unwrap();
+                self.push_front(token);
+                return;
+            }
+        }
+
+        while !(self.depth == 0 && matches!(self.last, Some(Token::SemiColon | Token::CloseBrace)))
+        {
+            if self.pop_front().is_none() {
+                return;
+            }
+        }
+    }
 }
 
 #[derive(Logos, Debug, PartialEq, Clone)]
 pub enum Token {
     #[error]
     #[regex(r"[ \t\n\f]+", logos::skip)]
+    #[regex(r"//[^\n]*", logos::skip)]
     Error,
 
     #[token("import")]
@@ -26,10 +141,22 @@ pub enum Token {
     From,
     #[token("let")]
     Let,
+    #[token("fn")]
+    Fn,
+    #[token("return")]
+    Return,
     #[token("for")]
     For,
     #[token("in")]
     In,
+    #[token("if")]
+    If,
+    #[token("else")]
+    Else,
+    #[token("true")]
+    True,
+    #[token("false")]
+    False,
 
     #[token("{")]
     OpenBrace,
@@ -41,8 +168,15 @@ pub enum Token {
     #[token(")")]
     CloseParen,
 
+    #[token("[")]
+    OpenBracket,
+    #[token("]")]
+    CloseBracket,
+
     #[regex("[a-zA-Z][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
     Ident(String),
+    #[regex("[0-9]+", |lex| lex.slice().parse())]
+    Number(usize),
 
     #[token(",")]
     Comma,
@@ -56,13 +190,122 @@ pub enum Token {
     #[token(".")]
     Period,
 
-    #[regex("\"[^\"]*\"", |lex| lex.slice()[1..(lex.slice().len()-1)].to_string())]
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unescape(&lex.slice()[1..(lex.slice().len()-1)]))]
+    #[regex(r#"r#*""#, raw_string)]
     StringLiteral(String),
-    #[regex("/[^/]+/", |lex| lex.slice()[1..(lex.slice().len()-1)].to_string())]
+    #[regex(r"/([^/\\]|\\.)+/", |lex| lex.slice()[1..(lex.slice().len()-1)].replace(r"\/", "/"))]
     Regex(String),
 
     #[token("@")]
     Directive,
     #[token("=")]
     Equal,
+    #[token("==")]
+    EqualEqual,
+    #[token("!=")]
+    NotEqual,
+    #[token("!")]
+    Not,
+    #[token("&&")]
+    And,
+    #[token("||")]
+    Or,
+
+    #[token("|")]
+    Pipe,
+    #[token("?")]
+    Question,
+    #[token("*")]
+    Star,
+    #[token("+")]
+    Plus,
+}
+
+/// Contents of a string literal with its escape sequences replaced, or `None` if one is invalid.
+fn unescape(s: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        result.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            _ => return None,
+        });
+    }
+    Some(result)
+}
+
+/// Lexes the rest of a raw string like `r#"a "quoted" word"#` once its start has been matched.
+fn raw_string(lex: &mut Lexer<Token>) -> Option<String> {
+    let hashes = lex.slice().len() - 2;
+    let end = format!("\"{}", "#".repeat(hashes));
+
+    let len = lex.remainder().find(&end)?;
+    let contents = lex.remainder()[..len].to_string();
+    lex.bump(len + end.len());
+    Some(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<Token> {
+        let mut tokens = lex(s).unwrap();
+        std::iter::from_fn(|| tokens.pop_front()).collect()
+    }
+
+    fn string(s: &str) -> Token {
+        Token::StringLiteral(s.to_string())
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            tokens(r#""a\"b" "\n\t\\""#),
+            vec![string("a\"b"), string("\n\t\\")]
+        );
+    }
+
+    #[test]
+    fn invalid_escape() {
+        let errors = lex(r#"let a = "\q";"#).unwrap_err();
+        assert_eq!(errors[0].span, 8..12);
+    }
+
+    #[test]
+    fn raw_strings() {
+        assert_eq!(
+            tokens(r###"r"a\n" r#"say "hi""# r##"a"#b"##"###),
+            vec![string(r"a\n"), string(r#"say "hi""#), string(r##"a"#b"##)]
+        );
+    }
+
+    #[test]
+    fn regex_with_slash() {
+        assert_eq!(
+            tokens(r"/a\/b\d/"),
+            vec![Token::Regex(r"a/b\d".to_string())]
+        );
+    }
+
+    #[test]
+    fn line_comments() {
+        assert_eq!(
+            tokens("// Comment\nfoo // \"not a string\"\n/a/"),
+            vec![
+                Token::Ident("foo".to_string()),
+                Token::Regex("a".to_string())
+            ]
+        );
+    }
 }
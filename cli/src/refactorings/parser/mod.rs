@@ -1,18 +1,38 @@
 #![allow(dead_code)]
 
 use super::script::*;
+use std::{fmt, ops::Range, rc::Rc};
 
+mod diagnostic;
 mod lex;
+pub use diagnostic::{render_all, Diagnostic};
 use lex::{lex, Token, Tokens};
 
-type Result<T> = std::result::Result<T, String>;
+type Result<T> = std::result::Result<T, Diagnostic>;
 
-pub fn parse(s: &str) -> Result<Script> {
+/// Parses a whole script, reporting every error found rather than only the first.
+pub fn parse(s: &str) -> std::result::Result<Script, Vec<Diagnostic>> {
     let mut tokens = lex(s)?;
 
     let mut top_levels = Vec::new();
-    while let Some(top_level) = top_level(&mut tokens)? {
-        top_levels.push(top_level);
+    let mut errors = Vec::new();
+    loop {
+        let remaining = tokens.remaining();
+        match top_level(&mut tokens) {
+            Ok(Some(top_level)) => top_levels.push(top_level),
+            Ok(None) => break,
+            Err(e) => {
+                errors.push(e);
+                tokens.recover();
+                if tokens.remaining() == remaining {
+                    tokens.pop_front();
+                }
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
     }
     Ok(Script::new(top_levels))
 }
@@ -21,47 +41,95 @@ pub fn parse(s: &str) -> Result<Script> {
 pub enum TopLevel {
     Import(Import),
     Directive(Directive),
-    Stmt(Stmt),
+    Function(Rc<Function>),
+    Stmt(Stmt, Span),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Import {
     pub idents: Vec<String>,
     pub source: String,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct Directive {
     pub name: String,
     pub value: String,
+    pub span: Span,
+}
+
+/// `fn name(params) { body }`
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub enum Stmt {
     ForLoop(String, Expr, Vec<Stmt>),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
     Expr(Expr),
     Assignment(String, Expr),
+    Return(Expr, Span),
 }
 
 #[derive(Debug)]
 pub enum Expr {
     Binding(String, Box<Expr>),
     DotAccess(Box<Expr>, String),
-    FnCall(String, Vec<Expr>),
-    MethodCall(Box<Expr>, String, Vec<Expr>),
+    FnCall(String, Vec<Expr>, Span),
+    MethodCall(Box<Expr>, String, Vec<Expr>, Span),
+    Index(Box<Expr>, Box<Expr>, Span),
 
-    Ident(String),
+    Ident(String, Span),
     StringLiteral(String),
     Regex(regex::Regex),
+    Bool(bool),
+    Number(usize),
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
 
     Concatenate(Box<Expr>, Box<Expr>),
+    Alternation(Box<Expr>, Box<Expr>),
+    Repeat(Box<Expr>, Repetition),
+    Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+/// Where a node came from in the script's source.
+///
+/// Left out of `Debug` output, so trees parsed from different sources can be compared.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct Span(pub Range<usize>);
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("_")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repetition {
+    /// `?`
+    Optional,
+    /// `*`
+    ZeroOrMore,
+    /// `+`
+    OneOrMore,
 }
 
 impl Stmt {
     fn requires_terminal(&self) -> bool {
         match self {
-            Stmt::ForLoop(_, _, _) => false,
-            Stmt::Expr(_) | Stmt::Assignment(_, _) => true,
+            Stmt::ForLoop(_, _, _) | Stmt::If(_, _, _) => false,
+            Stmt::Expr(_) | Stmt::Assignment(_, _) | Stmt::Return(_, _) => true,
         }
     }
 }
@@ -72,42 +140,70 @@ fn top_level(t: &mut Tokens) -> Result<Option<TopLevel>> {
 
         Some(Token::Import) => Ok(Some(TopLevel::Import(import(t)?))),
         Some(Token::Directive) => Ok(Some(TopLevel::Directive(directive(t)?))),
+        Some(Token::Fn) => Ok(Some(TopLevel::Function(function(t)?.into()))),
 
         Some(unhandled) => {
             t.push_front(unhandled);
+            let start = t.next_span().start;
 
             let s = stmt(t)?;
             if s.requires_terminal() {
                 take(t, Token::SemiColon)?;
             }
-            Ok(Some(TopLevel::Stmt(s)))
+            Ok(Some(TopLevel::Stmt(s, t.span_from(start))))
         }
     }
 }
 
 fn import(t: &mut Tokens) -> Result<Import> {
+    let start = t.last_span().start;
     take(t, Token::OpenBrace)?;
 
-    let idents = take_until(t, Token::CloseBrace, Token::Comma, |t| take_ident(t))?;
+    let idents = take_until(t, Token::CloseBrace, Token::Comma, take_ident)?;
 
     take(t, Token::From)?;
     let source = take_string_lit(t)?;
     take(t, Token::SemiColon)?;
 
-    Ok(Import { idents, source })
+    Ok(Import {
+        idents,
+        source,
+        span: t.span_from(start),
+    })
 }
 
 fn directive(t: &mut Tokens) -> Result<Directive> {
+    let start = t.last_span().start;
     let name = take_ident(t)?;
     take(t, Token::Equal)?;
     let value = take_string_lit(t)?;
     take(t, Token::SemiColon)?;
 
-    Ok(Directive { name, value })
+    Ok(Directive {
+        name,
+        value,
+        span: t.span_from(start),
+    })
+}
+
+fn function(t: &mut Tokens) -> Result<Function> {
+    let start = t.last_span().start;
+    let name = take_ident(t)?;
+    take(t, Token::OpenParen)?;
+    let params = take_until(t, Token::CloseParen, Token::Comma, take_ident)?;
+    let span = t.span_from(start);
+    let body = body(t)?;
+
+    Ok(Function {
+        name,
+        params,
+        body,
+        span,
+    })
 }
 
 fn expr(t: &mut Tokens) -> Result<Expr> {
-    concatenate_expr(t)
+    or_expr(t)
 }
 
 /// Left associative infix parsing.
@@ -134,50 +230,99 @@ macro_rules! impl_infix_parser {
     };
 }
 
-impl_infix_parser!(concatenate_expr, binding_expr, [
+impl_infix_parser!(or_expr, and_expr, [
+    Token::Or => Or,
+]);
+
+impl_infix_parser!(and_expr, equality_expr, [
+    Token::And => And,
+]);
+
+impl_infix_parser!(equality_expr, alternation_expr, [
+    Token::EqualEqual => Equal,
+    Token::NotEqual => NotEqual,
+]);
+
+impl_infix_parser!(alternation_expr, concatenate_expr, [
+    Token::Pipe => Alternation,
+]);
+
+impl_infix_parser!(concatenate_expr, not_expr, [
     Token::Concatenate => Concatenate,
 ]);
 
+fn not_expr(t: &mut Tokens) -> Result<Expr> {
+    if try_take(t, &Token::Not) {
+        return Ok(Expr::Not(not_expr(t)?.into()));
+    }
+
+    binding_expr(t)
+}
+
 fn binding_expr(t: &mut Tokens) -> Result<Expr> {
-    if let (Some(Token::Ident(_)), Some(Token::Colon)) = (t.get(0), t.get(1)) {
+    if let (Some(Token::Ident(_)), Some(Token::Colon)) = (t.front(), t.get(1)) {
         let binding = take_ident(t)?;
         take(t, Token::Colon)?;
 
-        let expr = fn_expr(t)?;
+        let expr = repeat_expr(t)?;
         return Ok(Expr::Binding(binding, expr.into()));
     }
 
-    fn_expr(t)
+    repeat_expr(t)
 }
 
-fn fn_expr(t: &mut Tokens) -> Result<Expr> {
-    if let (Some(Token::Ident(_)), Some(Token::OpenParen)) = (t.get(0), t.get(1)) {
-        let var = take_ident(t)?;
-        take(t, Token::OpenParen)?;
-        let args = take_until(t, Token::CloseParen, Token::Comma, |t| expr(t))?;
+fn repeat_expr(t: &mut Tokens) -> Result<Expr> {
+    let mut e = dot_access_expr(t)?;
 
-        return Ok(Expr::FnCall(var.to_string(), args));
+    loop {
+        let repetition = match t.front() {
+            Some(Token::Question) => Repetition::Optional,
+            Some(Token::Star) => Repetition::ZeroOrMore,
+            Some(Token::Plus) => Repetition::OneOrMore,
+            _ => return Ok(e),
+        };
+        t.pop_front();
+        e = Expr::Repeat(e.into(), repetition);
     }
-
-    dot_access_expr(t)
 }
 
 fn dot_access_expr(t: &mut Tokens) -> Result<Expr> {
-    let mut e = paren_expr(t)?;
+    let mut e = fn_expr(t)?;
 
     loop {
+        if try_take(t, &Token::OpenBracket) {
+            let start = t.last_span().start;
+            let index = expr(t)?;
+            take(t, Token::CloseBracket)?;
+            e = Expr::Index(e.into(), index.into(), t.span_from(start));
+            continue;
+        }
         if !try_take(t, &Token::Period) {
             return Ok(e);
         }
 
+        let start = t.next_span().start;
         let prop = take_ident(t)?;
-        if try_take(t, &Token::OpenParen) {
-            let args = take_until(t, Token::CloseParen, Token::Comma, |t| expr(t))?;
-            return Ok(Expr::MethodCall(e.into(), prop, args));
-        }
+        e = if try_take(t, &Token::OpenParen) {
+            let args = take_until(t, Token::CloseParen, Token::Comma, expr)?;
+            Expr::MethodCall(e.into(), prop, args, t.span_from(start))
+        } else {
+            Expr::DotAccess(e.into(), prop)
+        };
+    }
+}
+
+fn fn_expr(t: &mut Tokens) -> Result<Expr> {
+    if let (Some(Token::Ident(_)), Some(Token::OpenParen)) = (t.front(), t.get(1)) {
+        let start = t.next_span().start;
+        let var = take_ident(t)?;
+        take(t, Token::OpenParen)?;
+        let args = take_until(t, Token::CloseParen, Token::Comma, expr)?;
 
-        e = Expr::DotAccess(e.into(), prop);
+        return Ok(Expr::FnCall(var.to_string(), args, t.span_from(start)));
     }
+
+    paren_expr(t)
 }
 
 fn paren_expr(t: &mut Tokens) -> Result<Expr> {
@@ -194,18 +339,56 @@ fn leaf_expr(t: &mut Tokens) -> Result<Expr> {
     match t.pop_front() {
         Some(Token::StringLiteral(s)) => Ok(Expr::StringLiteral(s)),
         Some(Token::Regex(s)) => Ok(Expr::Regex(
-            regex::Regex::new(&s).map_err(|e| e.to_string())?,
+            regex::Regex::new(&s).map_err(|e| t.error(e.to_string()))?,
         )),
-        Some(Token::Ident(i)) => Ok(Expr::Ident(i)),
-
-        None => Err(format!("Expected expr, found EOF")),
-        Some(unexpected) => Err(format!("Expected expr, found {:?}", unexpected)),
+        Some(Token::Ident(i)) => Ok(Expr::Ident(i, t.span_from(t.last_span().start))),
+        Some(Token::True) => Ok(Expr::Bool(true)),
+        Some(Token::False) => Ok(Expr::Bool(false)),
+        Some(Token::Number(n)) => Ok(Expr::Number(n)),
+        Some(Token::OpenBracket) => Ok(Expr::List(take_until(
+            t,
+            Token::CloseBracket,
+            Token::Comma,
+            expr,
+        )?)),
+        Some(Token::OpenBrace) => Ok(Expr::Map(take_until(
+            t,
+            Token::CloseBrace,
+            Token::Comma,
+            map_entry,
+        )?)),
+
+        None => Err(t.error("Expected expr, found EOF".to_string())),
+        Some(unexpected) => Err(t.error(format!("Expected expr, found {:?}", unexpected))),
     }
 }
 
+/// `key: value` in a map literal.
+///
+/// Keys are parsed without bindings, so `{a: b}` is the variable `a` mapped to `b`.
+fn map_entry(t: &mut Tokens) -> Result<(Expr, Expr)> {
+    let key = dot_access_expr(t)?;
+    take(t, Token::Colon)?;
+    let value = expr(t)?;
+    Ok((key, value))
+}
+
+/// Statements in braces, where the last one needs no semicolon.
 fn body(t: &mut Tokens) -> Result<Vec<Stmt>> {
     take(t, Token::OpenBrace)?;
-    let stmts = take_until(t, Token::CloseBrace, Token::SemiColon, |t| stmt(t))?;
+
+    let mut stmts = Vec::new();
+    while !try_take(t, &Token::CloseBrace) {
+        let s = stmt(t)?;
+        let terminated = try_take(t, &Token::SemiColon);
+        let requires_terminal = s.requires_terminal();
+        stmts.push(s);
+
+        if requires_terminal && !terminated {
+            take(t, Token::CloseBrace)?;
+            break;
+        }
+    }
     Ok(stmts)
 }
 
@@ -226,6 +409,26 @@ fn stmt(t: &mut Tokens) -> Result<Stmt> {
             Ok(Stmt::ForLoop(ident, e, body))
         }
 
+        Token::If => {
+            let condition = expr(t)?;
+            let body = body(t)?;
+
+            let else_body = if !try_take(t, &Token::Else) {
+                Vec::new()
+            } else if t.front() == Some(&Token::If) {
+                vec![stmt(t)?]
+            } else {
+                self::body(t)?
+            };
+            Ok(Stmt::If(condition, body, else_body))
+        }
+
+        Token::Return => {
+            let start = t.last_span().start;
+            let e = expr(t)?;
+            Ok(Stmt::Return(e, t.span_from(start)))
+        }
+
         unhandled => {
             t.push_front(unhandled);
 
@@ -238,17 +441,17 @@ fn stmt(t: &mut Tokens) -> Result<Stmt> {
 fn take(t: &mut Tokens, expected: Token) -> Result<()> {
     let front = t
         .pop_front()
-        .ok_or_else(|| format!("Expected {:?}, found EOF", expected))?;
+        .ok_or_else(|| t.error(format!("Expected {:?}, found EOF", expected)))?;
     if front == expected {
         Ok(())
     } else {
-        Err(format!("Expected {:?}, found {:?}", expected, front))
+        Err(t.error(format!("Expected {:?}, found {:?}", expected, front)))
     }
 }
 
 fn take_any(t: &mut Tokens) -> Result<Token> {
     t.pop_front()
-        .ok_or_else(|| format!("Expected a token, found EOF"))
+        .ok_or_else(|| t.error("Expected a token, found EOF".to_string()))
 }
 
 fn take_one(t: &mut Tokens, expected: &[Token]) -> Result<Token> {
@@ -262,21 +465,21 @@ fn take_one(t: &mut Tokens, expected: &[Token]) -> Result<Token> {
 
     let front = t
         .pop_front()
-        .ok_or_else(|| format!("Expected one of {}, found EOF", tokens_message()))?;
+        .ok_or_else(|| t.error(format!("Expected one of {}, found EOF", tokens_message())))?;
 
     if expected.contains(&front) {
         Ok(front)
     } else {
-        Err(format!(
+        Err(t.error(format!(
             "Expected one of {}, found {:?}",
             tokens_message(),
             front
-        ))
+        )))
     }
 }
 
 fn try_take(tokens: &mut Tokens, expected: &Token) -> bool {
-    match tokens.get(0) {
+    match tokens.front() {
         Some(t) if t == expected => {
             tokens.pop_front();
             true
@@ -287,17 +490,17 @@ fn try_take(tokens: &mut Tokens, expected: &Token) -> bool {
 
 fn take_ident(t: &mut Tokens) -> Result<String> {
     match t.pop_front() {
-        None => Err(format!("Expected ident, found EOF")),
+        None => Err(t.error("Expected ident, found EOF".to_string())),
         Some(Token::Ident(s)) => Ok(s),
-        Some(t) => Err(format!("Expected ident, found {:?}", t)),
+        Some(unexpected) => Err(t.error(format!("Expected ident, found {:?}", unexpected))),
     }
 }
 
 fn take_string_lit(t: &mut Tokens) -> Result<String> {
     match t.pop_front() {
-        None => Err(format!("Expected string, found EOF")),
+        None => Err(t.error("Expected string, found EOF".to_string())),
         Some(Token::StringLiteral(s)) => Ok(s),
-        Some(t) => Err(format!("Expected string, found {:?}", t)),
+        Some(unexpected) => Err(t.error(format!("Expected string, found {:?}", unexpected))),
     }
 }
 
@@ -334,13 +537,149 @@ mod tests {
         parse("foo.bar();").unwrap();
     }
 
+    #[test]
+    fn chained_method_calls() {
+        let script = parse(r#"find("a").text.trim().to_upper();"#).unwrap();
+
+        assert_eq!(
+            format!("{:?}", script),
+            format!(
+                "{:?}",
+                parse(r#"((((find("a")).text).trim()).to_upper());"#).unwrap()
+            )
+        );
+    }
+
     #[test]
     fn no_semicolon_after_for() {
         parse("for foo in bar() {}").unwrap();
     }
 
+    #[test]
+    fn blocks_in_body() {
+        let script = parse(r#"for a in b { if c { d(); } for e in f {} g(); h() }"#).unwrap();
+
+        match &script.top_levels()[0] {
+            TopLevel::Stmt(Stmt::ForLoop(_, _, body), _) => assert_eq!(body.len(), 4),
+            unexpected => panic!("Expected for loop, found {:?}", unexpected),
+        }
+    }
+
+    #[test]
+    fn if_else() {
+        parse(r#"if foo == "bar" { baz(); } else if !qux { abort("no"); } else {}"#).unwrap();
+    }
+
+    #[test]
+    fn boolean_precedence() {
+        let script = parse(r#"let x = a || b && "c" .. d != e;"#).unwrap();
+
+        assert_eq!(
+            format!("{:?}", script),
+            format!(
+                "{:?}",
+                parse(r#"let x = a || (b && (("c" .. d) != e));"#).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn pattern_combinators() {
+        let script = parse(r#"find(a:"a"? .. "b" | c:("c" .. d:"d")* .. "e"+);"#).unwrap();
+
+        assert_eq!(
+            format!("{:?}", script),
+            format!(
+                "{:?}",
+                parse(r#"find(((a:("a"?)) .. "b") | ((c:(("c" .. (d:"d"))*)) .. ("e"+)));"#)
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn function() {
+        let script = parse(r#"fn wrap(a, b) { let c = a .. b; return c; }"#).unwrap();
+
+        match &script.top_levels()[0] {
+            TopLevel::Function(f) => {
+                assert_eq!(f.name, "wrap");
+                assert_eq!(f.params, vec!["a", "b"]);
+                assert_eq!(f.body.len(), 2);
+                assert_eq!(f.span.0, 0..13);
+            }
+            unexpected => panic!("Expected function, found {:?}", unexpected),
+        }
+    }
+
+    #[test]
+    fn list_and_map_literals() {
+        let script = parse(r#"let x = [a, "b"][0]; let y = {"<": ">=", a: [], }[op];"#).unwrap();
+
+        assert_eq!(
+            format!("{:?}", script),
+            format!(
+                "{:?}",
+                parse(r#"let x = ([a, "b"])[0]; let y = ({"<": ">=", a: []})[op];"#).unwrap()
+            )
+        );
+    }
+
     #[test]
     fn requires_semicolon_after_expr_as_stmt() {
         parse("foo()").unwrap_err();
     }
+
+    fn errors(s: &str) -> Vec<(std::ops::Range<usize>, String)> {
+        parse(s)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.span, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn error_spans() {
+        assert_eq!(
+            errors(r#"let a = "a" b;"#),
+            vec![(
+                12..13,
+                r#"Expected SemiColon, found Ident("b")"#.to_string()
+            )]
+        );
+        assert_eq!(
+            errors("find(a"),
+            vec![(6..6, "Expected CloseParen, found EOF".to_string())]
+        );
+        assert_eq!(
+            errors("let a = #;"),
+            vec![(8..9, r##"Unexpected "#""##.to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_every_statement() {
+        let errors = errors(
+            r#"let a = ;
+            for x in y { foo bar; baz(); }
+            let b = "b"
+            let c = (;
+            find(a);"#,
+        );
+
+        assert_eq!(
+            errors.iter().map(|(_, m)| m.as_str()).collect::<Vec<_>>(),
+            vec![
+                "Expected expr, found SemiColon",
+                r#"Expected CloseBrace, found Ident("bar")"#,
+                "Expected SemiColon, found Let",
+                "Expected expr, found SemiColon",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_lex_error() {
+        assert_eq!(errors("let a = #; let b = $;").len(), 2);
+    }
 }
@@ -0,0 +1,795 @@
+use super::{parser::Repetition, syntax::SyntaxTree};
+use regex::Regex;
+use regex_automata::{
+    hybrid::{
+        dfa::{Cache, DFA},
+        LazyStateID,
+    },
+    meta, Anchored, Input, MatchKind,
+};
+use regex_syntax::hir::{Hir, Look};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Range,
+    rc::Rc,
+};
+
+/// A pattern expression with every variable and argument evaluated.
+#[derive(Debug)]
+pub enum Node {
+    Literal(String),
+    Regex(Regex),
+    /// Text where every opening delimiter has a matching closing delimiter.
+    Balanced(String, String),
+    /// A syntax node of a kind, binding the children in its fields.
+    Syntax(String),
+    Concatenate(Box<Node>, Box<Node>),
+    Alternation(Box<Node>, Box<Node>),
+    Repeat(Box<Node>, Repetition),
+    Binding(String, Box<Node>),
+}
+
+/// A match of a whole pattern.
+#[derive(Debug)]
+pub struct Found {
+    pub range: Range<usize>,
+    /// Range of each binding and regex named group, in the order they finished matching.
+    pub bindings: Vec<(String, Range<usize>)>,
+}
+
+/// A pattern compiled to a program for a backtracking matcher.
+///
+/// Every step that can match in more than one way is retried with each alternative until the
+/// rest of the pattern matches. A step of the program that failed at some offset is never tried
+/// at that offset again, so scanning the text does not re-run the same comparisons.
+#[derive(Debug)]
+pub struct Pattern {
+    program: Vec<Inst>,
+    regexes: Vec<PatternRegex>,
+    names: Vec<String>,
+    /// Names bound inside a repetition, which may be bound any number of times.
+    repeated: Vec<String>,
+    /// The last text searched, parsed, if the pattern has syntax steps.
+    syntax: RefCell<Option<Rc<SyntaxTree>>>,
+}
+
+#[derive(Debug)]
+enum Inst {
+    Literal(String),
+    Regex(usize),
+    Balanced(String, String),
+    Syntax(String),
+    /// Continue at the first target, and at the second if that fails.
+    Split(usize, usize),
+    Jump(usize),
+    BindStart,
+    BindEnd(usize),
+    Match,
+}
+
+impl Pattern {
+    pub fn compile(node: &Node) -> Self {
+        let mut pattern = Pattern {
+            program: Vec::new(),
+            regexes: Vec::new(),
+            names: Vec::new(),
+            repeated: Vec::new(),
+            syntax: RefCell::new(None),
+        };
+        pattern.emit(node, false);
+        pattern.program.push(Inst::Match);
+        pattern
+    }
+
+    pub fn repeated(&self) -> &[String] {
+        &self.repeated
+    }
+
+    /// Whether the pattern matches syntax nodes, so the text needs parsing.
+    pub fn has_syntax(&self) -> bool {
+        self.program
+            .iter()
+            .any(|inst| matches!(inst, Inst::Syntax(_)))
+    }
+
+    fn emit(&mut self, node: &Node, in_repetition: bool) {
+        match node {
+            Node::Literal(s) => self.program.push(Inst::Literal(s.clone())),
+
+            Node::Regex(re) => {
+                if in_repetition {
+                    for name in re.capture_names().flatten() {
+                        self.add_repeated(name);
+                    }
+                }
+                self.program.push(Inst::Regex(self.regexes.len()));
+                self.regexes.push(PatternRegex::new(re));
+            }
+
+            Node::Balanced(open, close) => self
+                .program
+                .push(Inst::Balanced(open.clone(), close.clone())),
+
+            Node::Syntax(kind) => self.program.push(Inst::Syntax(kind.clone())),
+
+            Node::Concatenate(left, right) => {
+                self.emit(left, in_repetition);
+                self.emit(right, in_repetition);
+            }
+
+            Node::Alternation(left, right) => {
+                let split = self.placeholder();
+                self.emit(left, in_repetition);
+                let jump = self.placeholder();
+                let right_start = self.program.len();
+                self.emit(right, in_repetition);
+
+                self.program[split] = Inst::Split(split + 1, right_start);
+                self.program[jump] = Inst::Jump(self.program.len());
+            }
+
+            Node::Repeat(node, Repetition::Optional) => {
+                let split = self.placeholder();
+                self.emit(node, true);
+                self.program[split] = Inst::Split(split + 1, self.program.len());
+            }
+            Node::Repeat(node, Repetition::ZeroOrMore) => {
+                let split = self.placeholder();
+                self.emit(node, true);
+                self.program.push(Inst::Jump(split));
+                self.program[split] = Inst::Split(split + 1, self.program.len());
+            }
+            Node::Repeat(node, Repetition::OneOrMore) => {
+                let start = self.program.len();
+                self.emit(node, true);
+                self.program
+                    .push(Inst::Split(start, self.program.len() + 1));
+            }
+
+            Node::Binding(name, node) => {
+                if in_repetition {
+                    self.add_repeated(name);
+                }
+                let index = match self.names.iter().position(|n| n == name) {
+                    Some(index) => index,
+                    None => {
+                        self.names.push(name.clone());
+                        self.names.len() - 1
+                    }
+                };
+
+                self.program.push(Inst::BindStart);
+                self.emit(node, in_repetition);
+                self.program.push(Inst::BindEnd(index));
+            }
+        }
+    }
+
+    fn placeholder(&mut self) -> usize {
+        self.program.push(Inst::Match);
+        self.program.len() - 1
+    }
+
+    fn add_repeated(&mut self, name: &str) {
+        if !self.repeated.iter().any(|n| n == name) {
+            self.repeated.push(name.to_string());
+        }
+    }
+
+    /// Leftmost match in `contents` starting at or after `start`.
+    pub fn find_at(&self, contents: &str, start: usize) -> Option<Found> {
+        let mut search = Search {
+            failed: HashSet::new(),
+            scanned: self.regexes.iter().map(|_| Scanned::default()).collect(),
+            balanced: HashMap::new(),
+            start,
+        };
+        let mut at = start;
+        let mut first_step: Option<Range<usize>> = None;
+
+        while at <= contents.len() {
+            // Inside the last match of the first step, every offset is worth trying.
+            at = match &first_step {
+                Some(m) if at < m.end => at,
+                _ => {
+                    let m = self.first_step_match(contents, at)?;
+                    at = m.start;
+                    first_step = Some(m);
+                    at
+                }
+            };
+            search.start = at;
+            if let Some((end, log)) = self.run(contents, &mut search) {
+                return Some(self.found(contents, at..end, log));
+            }
+            at = next_char_boundary(contents, at);
+        }
+
+        None
+    }
+
+    /// Leftmost match at or after `at` of the first step of the program, or all of the text from
+    /// `at` if that step can't be searched for on its own.
+    fn first_step_match(&self, contents: &str, at: usize) -> Option<Range<usize>> {
+        let first = self
+            .program
+            .iter()
+            .find(|inst| !matches!(inst, Inst::BindStart));
+
+        match first {
+            Some(Inst::Literal(s)) => {
+                let start = at + contents[at..].find(s.as_str())?;
+                Some(start..start + s.len())
+            }
+            Some(Inst::Regex(i)) => self.regexes[*i]
+                .regex
+                .find_at(contents, at)
+                .map(|m| m.range()),
+            _ => Some(at..contents.len()),
+        }
+    }
+
+    /// End of the preferred match starting exactly at `search.start`, with the events that led
+    /// to it.
+    fn run(&self, contents: &str, search: &mut Search) -> Option<(usize, Option<Rc<Log>>)> {
+        let mut stack = vec![(0, search.start, None)];
+
+        while let Some((mut pc, mut at, mut log)) = stack.pop() {
+            while search.failed.insert((pc, at)) {
+                match &self.program[pc] {
+                    Inst::Literal(_) | Inst::Regex(_) | Inst::Balanced(_, _) | Inst::Syntax(_) => {
+                        let mut ends = self.ends(pc, contents, at, search).into_iter();
+                        let end = match ends.next() {
+                            Some(end) => end,
+                            None => break,
+                        };
+
+                        for other in ends.rev() {
+                            stack.push((pc + 1, other, self.log_step(pc, at..other, &log)));
+                        }
+                        log = self.log_step(pc, at..end, &log);
+                        pc += 1;
+                        at = end;
+                    }
+
+                    Inst::Split(first, second) => {
+                        stack.push((*second, at, log.clone()));
+                        pc = *first;
+                    }
+                    Inst::Jump(target) => pc = *target,
+
+                    Inst::BindStart => {
+                        log = Log::push(log, Event::BindStart(at));
+                        pc += 1;
+                    }
+                    Inst::BindEnd(name) => {
+                        log = Log::push(log, Event::BindEnd(*name, at));
+                        pc += 1;
+                    }
+
+                    Inst::Match => return Some((at, log)),
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Ends of the matches of a single step starting at `at`, most preferred first.
+    fn ends(&self, pc: usize, contents: &str, at: usize, search: &mut Search) -> Vec<usize> {
+        match &self.program[pc] {
+            Inst::Literal(s) if contents[at..].starts_with(s.as_str()) => vec![at + s.len()],
+            Inst::Literal(_) => Vec::new(),
+            Inst::Regex(i) => {
+                self.regexes[*i].ends(contents, at, search.start, &mut search.scanned[*i])
+            }
+            Inst::Balanced(open, close) => {
+                let start = search.start;
+                let scanned = &mut search.balanced;
+                let mut ends = balanced_ends(
+                    contents,
+                    at,
+                    open,
+                    close,
+                    |i, depth| matches!(scanned.insert((pc, i, depth), start), Some(earlier) if earlier < start),
+                );
+                ends.reverse();
+                ends
+            }
+            Inst::Syntax(kind) => {
+                let mut ends = self
+                    .syntax_tree(contents)
+                    .nodes_at(at, kind)
+                    .map(|n| n.range.end)
+                    .collect::<Vec<_>>();
+                ends.dedup();
+                ends
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// The parsed `contents`, reusing the last parse if the text hasn't changed.
+    fn syntax_tree(&self, contents: &str) -> Rc<SyntaxTree> {
+        let mut syntax = self.syntax.borrow_mut();
+        match &*syntax {
+            Some(tree) if tree.text() == contents => tree.clone(),
+            _ => syntax.insert(Rc::new(SyntaxTree::parse(contents))).clone(),
+        }
+    }
+
+    fn log_step(&self, pc: usize, range: Range<usize>, log: &Option<Rc<Log>>) -> Option<Rc<Log>> {
+        match &self.program[pc] {
+            Inst::Regex(i) if self.regexes[*i].regex.captures_len() > 1 => {
+                Log::push(log.clone(), Event::Regex(*i, range))
+            }
+            Inst::Syntax(_) => Log::push(log.clone(), Event::Syntax(pc, range)),
+            _ => log.clone(),
+        }
+    }
+
+    fn found(&self, contents: &str, range: Range<usize>, mut log: Option<Rc<Log>>) -> Found {
+        let mut events = Vec::new();
+        while let Some(l) = log {
+            events.push(l.event.clone());
+            log = l.prev.clone();
+        }
+
+        let mut starts = Vec::new();
+        // Fields of syntax nodes come first, so bindings named in the pattern replace them.
+        let mut fields = Vec::new();
+        let mut bindings = Vec::new();
+        for event in events.into_iter().rev() {
+            match event {
+                Event::BindStart(at) => starts.push(at),
+                Event::BindEnd(name, at) => {
+                    let start = starts.pop().unwrap();
+                    bindings.push((self.names[name].clone(), start..at));
+                }
+                Event::Regex(i, range) => bindings.extend(self.regexes[i].groups(contents, range)),
+                Event::Syntax(pc, range) => {
+                    let kind = match &self.program[pc] {
+                        Inst::Syntax(kind) => kind,
+                        _ => unreachable!(),
+                    };
+                    let tree = self.syntax_tree(contents);
+                    let node = tree.nodes_at(range.start, kind).find(|n| n.range == range);
+                    fields.extend(node.into_iter().flat_map(|n| n.fields.clone()));
+                }
+            }
+        }
+
+        fields.extend(bindings);
+        Found {
+            range,
+            bindings: fields,
+        }
+    }
+}
+
+/// State shared by every start offset tried in one search.
+struct Search {
+    /// Steps and offsets already tried, which can only lead to failure again.
+    failed: HashSet<(usize, usize)>,
+    /// Where the scans of each regex have been.
+    scanned: Vec<Scanned>,
+    /// Offsets and nesting depths each balanced step's scans passed through, with the match start
+    /// being tried.
+    balanced: HashMap<(usize, usize, usize), usize>,
+    /// Offset the current match must start at.
+    start: usize,
+}
+
+/// The DFA states a regex's scans passed through, by offset, with the match start being tried.
+///
+/// A later scan reaching the same state at the same offset would only find the same ends again,
+/// and those have all failed if they were found while trying an earlier start.
+#[derive(Default)]
+struct Scanned {
+    states: HashMap<(LazyStateID, usize), usize>,
+    /// States are renumbered whenever the DFA's cache is cleared.
+    cache_clears: usize,
+}
+
+/// Something that happened on the way to a match, needed to work out its bindings.
+#[derive(Debug, Clone)]
+enum Event {
+    BindStart(usize),
+    BindEnd(usize, usize),
+    Regex(usize, Range<usize>),
+    /// A syntax step matched a node, by the step's position in the program.
+    Syntax(usize, Range<usize>),
+}
+
+/// Events of a path through the program, shared with the paths it branched from.
+#[derive(Debug)]
+struct Log {
+    event: Event,
+    prev: Option<Rc<Log>>,
+}
+
+impl Log {
+    fn push(prev: Option<Rc<Log>>, event: Event) -> Option<Rc<Log>> {
+        Some(Rc::new(Log { event, prev }))
+    }
+}
+
+#[derive(Debug)]
+struct PatternRegex {
+    regex: Regex,
+    /// Only matches up to the end of the text, to find the groups of a match with a given end.
+    ending: Option<meta::Regex>,
+    /// Lazy DFAs following the regex's own preference, and following every possible match.
+    dfas: Option<(DFA, DFA)>,
+    caches: RefCell<Option<(Cache, Cache)>>,
+}
+
+impl PatternRegex {
+    fn new(regex: &Regex) -> Self {
+        let dfa = |kind| {
+            DFA::builder()
+                .configure(DFA::config().match_kind(kind).unicode_word_boundary(true))
+                .build(regex.as_str())
+                .ok()
+        };
+        let dfas = dfa(MatchKind::LeftmostFirst).zip(dfa(MatchKind::All));
+        let caches = dfas
+            .as_ref()
+            .map(|(preferred, all)| (preferred.create_cache(), all.create_cache()));
+
+        PatternRegex {
+            regex: regex.clone(),
+            ending: ending(regex),
+            dfas,
+            caches: RefCell::new(caches),
+        }
+    }
+
+    /// Ends of every match starting at `start`, the regex's own match first then longest first.
+    ///
+    /// Ends that can only have been found already while trying an earlier `match_start` are left
+    /// out.
+    fn ends(
+        &self,
+        contents: &str,
+        start: usize,
+        match_start: usize,
+        scanned: &mut Scanned,
+    ) -> Vec<usize> {
+        if let Some(ends) = self.dfa_ends(contents, start, match_start, scanned) {
+            return ends;
+        }
+
+        match self.regex.find_at(contents, start) {
+            Some(m) if m.start() == start => vec![m.end()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Ends found by the lazy DFAs, or `None` if they can't search this text.
+    fn dfa_ends(
+        &self,
+        contents: &str,
+        start: usize,
+        match_start: usize,
+        scanned: &mut Scanned,
+    ) -> Option<Vec<usize>> {
+        let (preferred_dfa, all_dfa) = self.dfas.as_ref()?;
+        let mut caches = self.caches.borrow_mut();
+        let (preferred_cache, all_cache) = caches.as_mut()?;
+
+        let input = Input::new(contents).range(start..).anchored(Anchored::Yes);
+        let mut preferred_state = preferred_dfa
+            .start_state_forward(preferred_cache, &input)
+            .ok()?;
+        let mut all_state = all_dfa.start_state_forward(all_cache, &input).ok()?;
+
+        let mut preferred = None;
+        let mut ends = Vec::new();
+        let mut finished = true;
+        for (at, &byte) in contents.as_bytes().iter().enumerate().skip(start) {
+            if all_cache.clear_count() != scanned.cache_clears {
+                scanned.states.clear();
+                scanned.cache_clears = all_cache.clear_count();
+            }
+            match scanned.states.insert((all_state, at), match_start) {
+                Some(earlier) if earlier < match_start => {
+                    finished = false;
+                    break;
+                }
+                _ => {}
+            }
+
+            if !preferred_state.is_dead() {
+                preferred_state = preferred_dfa
+                    .next_state(preferred_cache, preferred_state, byte)
+                    .ok()?;
+                if preferred_state.is_match() {
+                    preferred = Some(at);
+                }
+            }
+            all_state = all_dfa.next_state(all_cache, all_state, byte).ok()?;
+            if all_state.is_match() {
+                ends.push(at);
+            }
+
+            if preferred_state.is_quit() || all_state.is_quit() {
+                return None;
+            }
+            if all_state.is_dead() {
+                break;
+            }
+        }
+
+        if finished && !all_state.is_dead() {
+            if !preferred_state.is_dead() {
+                preferred_state = preferred_dfa
+                    .next_eoi_state(preferred_cache, preferred_state)
+                    .ok()?;
+                if preferred_state.is_match() {
+                    preferred = Some(contents.len());
+                }
+            }
+            all_state = all_dfa.next_eoi_state(all_cache, all_state).ok()?;
+            if all_state.is_match() {
+                ends.push(contents.len());
+            }
+        }
+
+        let others = ends
+            .into_iter()
+            .rev()
+            .filter(|&end| Some(end) != preferred && contents.is_char_boundary(end));
+        Some(preferred.into_iter().chain(others).collect())
+    }
+
+    /// Named groups of the match spanning `range`.
+    fn groups(&self, contents: &str, range: Range<usize>) -> Vec<(String, Range<usize>)> {
+        let ending = match &self.ending {
+            Some(ending) => ending,
+            None => return Vec::new(),
+        };
+        let mut captures = ending.create_captures();
+        let input = Input::new(&contents[..range.end])
+            .range(range.start..)
+            .anchored(Anchored::Yes);
+        ending.search_captures(&input, &mut captures);
+        if !captures.is_match() {
+            return Vec::new();
+        }
+
+        self.regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), captures.get_group_by_name(name)?.range())))
+            .collect()
+    }
+}
+
+/// `regex` followed by the end of the text.
+///
+/// Built from the parsed regex rather than its source, which could end in a comment or an
+/// unclosed flag group that would swallow anything appended to it.
+fn ending(regex: &Regex) -> Option<meta::Regex> {
+    let hir = regex_syntax::parse(regex.as_str()).ok()?;
+    let hir = Hir::concat(vec![hir, Hir::look(Look::End)]);
+    meta::Regex::builder().build_from_hir(&hir).ok()
+}
+
+pub fn next_char_boundary(s: &str, index: usize) -> usize {
+    (index + 1..=s.len())
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(index + 1)
+}
+
+/// Every end of text from `start` where each `open` has a matching `close`, in order.
+///
+/// Delimiters inside string and char literals are ignored. The scan stops early when `seen`
+/// returns true for an offset and nesting depth.
+fn balanced_ends(
+    contents: &str,
+    start: usize,
+    open: &str,
+    close: &str,
+    mut seen: impl FnMut(usize, usize) -> bool,
+) -> Vec<usize> {
+    let mut depth = 0;
+    let mut ends = vec![start];
+    let mut i = start;
+
+    while i < contents.len() && !seen(i, depth) {
+        let rest = &contents[i..];
+        if let Some(len) = literal_len(rest) {
+            i += len;
+        } else if rest.starts_with('"') {
+            break;
+        } else if rest.starts_with(open) {
+            depth += 1;
+            i += open.len();
+        } else if rest.starts_with(close) {
+            if depth == 0 {
+                break;
+            }
+            depth -= 1;
+            i += close.len();
+        } else {
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+
+        if depth == 0 {
+            ends.push(i);
+        }
+    }
+
+    ends
+}
+
+/// Length of the string or char literal at the start of `s`.
+fn literal_len(s: &str) -> Option<usize> {
+    static LITERAL: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+    LITERAL
+        .get_or_init(|| Regex::new(r#"\A(?:"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)')"#).unwrap())
+        .find(s)
+        .map(|m| m.end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal(s: &str) -> Box<Node> {
+        Box::new(Node::Literal(s.to_string()))
+    }
+
+    fn regex(re: &str) -> Box<Node> {
+        Box::new(Node::Regex(Regex::new(re).unwrap()))
+    }
+
+    fn concat(left: Box<Node>, right: Box<Node>) -> Box<Node> {
+        Box::new(Node::Concatenate(left, right))
+    }
+
+    fn find(node: Box<Node>, contents: &str) -> Option<Range<usize>> {
+        Pattern::compile(&node)
+            .find_at(contents, 0)
+            .map(|f| f.range)
+    }
+
+    #[test]
+    fn backtracks_into_regex() {
+        assert_eq!(
+            find(concat(regex(r"\w+"), literal("b")), "a aab"),
+            Some(2..5)
+        );
+        assert_eq!(
+            find(concat(regex(r"\s+"), literal(" x")), "a   x"),
+            Some(1..5)
+        );
+    }
+
+    #[test]
+    fn regex_preference() {
+        assert_eq!(find(regex("a+?"), "aaa"), Some(0..1));
+        assert_eq!(find(concat(regex("a+?"), literal("b")), "aab"), Some(0..3));
+        assert_eq!(find(regex("a|ab"), "ab"), Some(0..1));
+    }
+
+    #[test]
+    fn backtracks_into_balanced() {
+        let args = concat(
+            Box::new(Node::Balanced("(".to_string(), ")".to_string())),
+            literal(","),
+        );
+        assert_eq!(find(args, "f(a), b)"), Some(0..5));
+    }
+
+    #[test]
+    fn alternation_prefers_left() {
+        let pattern = Node::Concatenate(
+            Box::new(Node::Alternation(
+                Box::new(Node::Binding("first".to_string(), literal("a"))),
+                Box::new(Node::Binding("second".to_string(), literal("ab"))),
+            )),
+            regex("b?"),
+        );
+
+        let found = Pattern::compile(&pattern).find_at("ab", 0).unwrap();
+        assert_eq!(found.range, 0..2);
+        assert_eq!(found.bindings, vec![("first".to_string(), 0..1)]);
+    }
+
+    #[test]
+    fn repeated_bindings() {
+        let pattern = Node::Repeat(
+            concat(
+                Box::new(Node::Binding("arg".to_string(), regex(r"\w+"))),
+                regex(",? *"),
+            ),
+            Repetition::ZeroOrMore,
+        );
+
+        let pattern = Pattern::compile(&pattern);
+        let found = pattern.find_at("a, bc, d", 0).unwrap();
+        assert_eq!(found.range, 0..8);
+        assert_eq!(
+            found.bindings,
+            vec![
+                ("arg".to_string(), 0..1),
+                ("arg".to_string(), 3..5),
+                ("arg".to_string(), 7..8),
+            ]
+        );
+        assert_eq!(pattern.repeated(), ["arg".to_string()]);
+    }
+
+    #[test]
+    fn empty_repetition_terminates() {
+        let pattern = Node::Repeat(regex("a*"), Repetition::ZeroOrMore);
+        assert_eq!(find(Box::new(pattern), "aab"), Some(0..2));
+    }
+
+    #[test]
+    fn regex_groups_of_chosen_match() {
+        let pattern = Node::Concatenate(regex(r"(?P<word>\w+)"), literal("c"));
+
+        let found = Pattern::compile(&pattern).find_at("abc", 0).unwrap();
+        assert_eq!(found.range, 0..3);
+        assert_eq!(found.bindings, vec![("word".to_string(), 0..2)]);
+    }
+
+    #[test]
+    fn verbose_regex_with_trailing_comment() {
+        let pattern = Node::Concatenate(regex(r"(?x) (?P<word> \w+ ) # a word"), literal("c"));
+
+        let found = Pattern::compile(&pattern).find_at("abc", 0).unwrap();
+        assert_eq!(found.range, 0..3);
+        assert_eq!(found.bindings, vec![("word".to_string(), 0..2)]);
+    }
+
+    #[test]
+    fn word_boundaries() {
+        assert_eq!(
+            find(concat(regex(r"\bb\w*"), literal("c")), "ab bc"),
+            Some(3..5)
+        );
+        assert_eq!(find(regex(r"\bé\w*"), "aé éc"), Some(4..7));
+    }
+
+    #[test]
+    fn long_failing_scan() {
+        let contents = " ".repeat(100_000);
+        assert_eq!(find(concat(regex(r"\s+"), literal("x")), &contents), None);
+
+        let contents = "(a) ".repeat(25_000);
+        let pattern = concat(
+            Box::new(Node::Balanced("(".to_string(), ")".to_string())),
+            literal("x"),
+        );
+        assert_eq!(find(pattern, &contents), None);
+    }
+
+    #[test]
+    fn balanced_ends_() {
+        let longest = |contents, start, open, close| {
+            *balanced_ends(contents, start, open, close, |_, _| false)
+                .last()
+                .unwrap()
+        };
+
+        assert_eq!(longest("a)", 0, "(", ")"), 1);
+        assert_eq!(longest("(a) + b)", 0, "(", ")"), 7);
+        assert_eq!(longest("x(a(b)c)d)", 1, "(", ")"), 9);
+        assert_eq!(longest("(a", 0, "(", ")"), 0);
+        assert_eq!(longest(r#"")" + ')' + "\")")"#, 0, "(", ")"), 17);
+        assert_eq!(longest(r#"a "b)"#, 0, "(", ")"), 2);
+        assert_eq!(longest("[a[0]]]", 0, "[", "]"), 6);
+        assert_eq!(longest("{ a: { b } }}", 0, "{", "}"), 12);
+        assert_eq!(longest("Vec<Option<'a, T>>>", 0, "<", ">"), 18);
+
+        assert_eq!(
+            balanced_ends("a(b)c)", 0, "(", ")", |_, _| false),
+            vec![0, 1, 4, 5]
+        );
+    }
+}
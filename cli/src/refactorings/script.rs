@@ -1,21 +1,123 @@
 use crate::{
-    refactorings::{parser::*, Mutation, Refactoring},
-    ContentRegion, EditorContext,
+    refactorings::{
+        case,
+        parser::*,
+        pattern::{next_char_boundary, Node, Pattern},
+        syntax,
+        workspace::{self, WorkspaceFile},
+        Coordinates, Input, InputType, KybError, Mutation, PerformError, Position, Refactoring,
+        TextEdit, TextEdits, TextRange,
+    },
+    ContentRegion, EditorContext, PositionEncoding,
 };
-use std::{collections::*, ops::Range};
+use std::{
+    cell::{Cell, RefCell},
+    collections::*,
+    fmt,
+    ops::Range,
+    path::Path,
+    rc::Rc,
+};
+
+/// Calls that can be in progress at once, so runaway recursion fails instead of overflowing.
+const MAX_CALL_DEPTH: usize = 64;
 
 #[derive(Debug)]
 pub struct Script {
     top_levels: Vec<TopLevel>,
+    /// Scripts imported from, by source.
+    modules: HashMap<String, Script>,
 }
 
 impl Refactoring for Script {
-    fn applies_to(&self, context: &EditorContext) -> bool {
-        self.exec(context).is_ok()
+    fn available_in(&self, context: &EditorContext) -> bool {
+        let listed = |directive_name| {
+            self.directive(directive_name).map(|d| {
+                d.value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let language_matches = match (listed("languages"), &context.language_id) {
+            (Some(languages), Some(language)) => languages.contains(&language.as_str()),
+            _ => true,
+        };
+        let file_matches = match (listed("files"), &context.file_path) {
+            (Some(globs), Some(path)) => {
+                let path = context
+                    .workspace_root
+                    .as_ref()
+                    .and_then(|root| Path::new(path).strip_prefix(root).ok())
+                    .map_or_else(|| path.clone(), |p| p.to_string_lossy().into_owned());
+                globs.iter().any(|g| workspace::glob_matches(g, &path))
+            }
+            _ => true,
+        };
+        // Without a syntax tree, `node` patterns can never match.
+        let syntax_matches =
+            !self.uses_syntax() || buffer_language(context).is_some_and(syntax::has_grammar);
+        language_matches && file_matches && syntax_matches
+    }
+
+    fn applies_to(&self, context: &EditorContext) -> Result<bool, KybError> {
+        // Not as keystrokes, which can't make edits to other files.
+        match self.text_edits(&Env::new(context, None), Coordinates::Offset) {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_bug() => Err(e),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn perform(
+        &self,
+        context: &EditorContext,
+        inputs: &HashMap<String, String>,
+    ) -> Result<Vec<Mutation>, PerformError> {
+        self.check_inputs(inputs)?;
+        let mutations = self.mutations(&Env::new(context, Some(inputs)))?;
+        Ok(mutations)
+    }
+
+    fn perform_edits(
+        &self,
+        context: &EditorContext,
+        inputs: &HashMap<String, String>,
+        coordinates: Coordinates,
+    ) -> Result<TextEdits, PerformError> {
+        self.check_inputs(inputs)?;
+        let edits = self.text_edits(&Env::new(context, Some(inputs)), coordinates)?;
+        Ok(edits)
     }
 
-    fn perform(&self, context: &EditorContext) -> Result<Vec<Mutation>, String> {
-        self.exec(context)
+    fn inputs(&self) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        for tl in &self.top_levels {
+            match tl {
+                TopLevel::Stmt(stmt, _) => stmt_inputs(stmt, &mut inputs),
+                TopLevel::Function(f) => {
+                    for stmt in &f.body {
+                        stmt_inputs(stmt, &mut inputs);
+                    }
+                }
+                // Imported modules run in full, so their inputs are needed as well.
+                TopLevel::Import(import) => {
+                    for input in self
+                        .modules
+                        .get(&import.source)
+                        .map_or(vec![], |m| m.inputs())
+                    {
+                        if !inputs.iter().any(|i| i.key == input.key) {
+                            inputs.push(input);
+                        }
+                    }
+                }
+                TopLevel::Directive(_) => {}
+            }
+        }
+        inputs
     }
 
     fn id(&self) -> String {
@@ -27,420 +129,3241 @@ impl Refactoring for Script {
     }
 
     fn description(&self) -> String {
-        self.directive_value("description")
+        self.directive("description")
+            .map(|d| d.value.clone())
+            .unwrap_or_default()
     }
 }
 
 impl Script {
     pub fn new(top_levels: Vec<TopLevel>) -> Self {
-        Script { top_levels }
+        Script {
+            top_levels,
+            modules: HashMap::new(),
+        }
+    }
+
+    pub fn imports(&self) -> impl Iterator<Item = &Import> {
+        self.top_levels.iter().filter_map(|t| match t {
+            TopLevel::Import(i) => Some(i),
+            _ => None,
+        })
+    }
+
+    pub fn add_module(&mut self, source: &str, module: Script) {
+        self.modules.insert(source.to_string(), module);
+    }
+
+    /// Whether the script has a top level `let` or `fn` named `ident`.
+    pub fn defines(&self, ident: &str) -> bool {
+        self.top_levels.iter().any(|t| match t {
+            TopLevel::Stmt(Stmt::Assignment(i, _), _) => i == ident,
+            TopLevel::Function(f) => f.name == ident,
+            _ => false,
+        })
     }
 
+    pub fn top_levels(&self) -> &[TopLevel] {
+        &self.top_levels
+    }
+
+    pub fn directive(&self, directive_name: &str) -> Option<&Directive> {
+        self.top_levels.iter().find_map(|t| match t {
+            TopLevel::Directive(d) if d.name == directive_name => Some(d),
+            _ => None,
+        })
+    }
+
+    fn check_inputs(&self, inputs: &HashMap<String, String>) -> Result<(), PerformError> {
+        match self
+            .inputs()
+            .into_iter()
+            .find(|i| !inputs.contains_key(&i.key))
+        {
+            Some(missing) => Err(PerformError::MissingInput(missing)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the script, or a module it imports, matches syntax nodes with `node`.
+    fn uses_syntax(&self) -> bool {
+        self.top_levels.iter().any(|tl| match tl {
+            TopLevel::Stmt(stmt, _) => stmt_uses_syntax(stmt),
+            TopLevel::Function(f) => f.body.iter().any(stmt_uses_syntax),
+            TopLevel::Import(import) => self
+                .modules
+                .get(&import.source)
+                .is_some_and(Script::uses_syntax),
+            TopLevel::Directive(_) => false,
+        })
+    }
+
+    /// Value of a directive the checker requires every refactoring to have.
     fn directive_value(&self, directive_name: &str) -> String {
-        self.top_levels
-            .iter()
-            .filter_map(|t| match t {
-                TopLevel::Directive(d) if d.name == directive_name => Some(d.value.clone()),
-                _ => None,
-            })
-            .next()
-            .unwrap()
+        self.directive(directive_name).unwrap().value.clone()
+    }
+
+    fn mutations(&self, env: &Env) -> Result<Vec<Mutation>, KybError> {
+        let edits = self.exec(env)?;
+        if edits.iter().any(|e| e.file.is_some()) {
+            return Err(KybError::EditsToOtherFiles);
+        }
+        let contents = env.context.contents_ref();
+        let encoding = env.context.position_encoding;
+
+        let mut mutations =
+            mutations(&edits, &contents, encoding).ok_or(KybError::OverlappingEdits)?;
+        if let Some(selection) = env.selection.take() {
+            let edited = edited(&all_contents(&contents), &edits);
+            mutations.push(selection_mutation(selection, &edits, &edited, encoding));
+        }
+        Ok(mutations)
+    }
+
+    fn text_edits(&self, env: &Env, coordinates: Coordinates) -> Result<TextEdits, KybError> {
+        let edits = self.exec(env)?;
+        text_edits(
+            &edits,
+            env.selection.take(),
+            &env.context.contents_ref(),
+            coordinates,
+            env.context.position_encoding,
+        )
+        .ok_or(KybError::OverlappingEdits)
+    }
+
+    fn exec(&self, env: &Env) -> Result<Vec<Edit>, KybError> {
+        self.exec_in(HashMap::new(), env)
     }
 
-    fn exec(&self, context: &EditorContext) -> Result<Vec<Mutation>, String> {
-        let mut result = Vec::new();
-        let mut scope = HashMap::new();
+    fn exec_in(&self, mut scope: HashMap<String, Value>, env: &Env) -> Result<Vec<Edit>, KybError> {
+        let mut edits = Vec::new();
+        self.exec_top_levels(&mut scope, env, &mut edits)?;
+        Ok(edits)
+    }
 
+    fn exec_top_levels(
+        &self,
+        scope: &mut HashMap<String, Value>,
+        env: &Env,
+        edits: &mut Vec<Edit>,
+    ) -> Result<(), KybError> {
         for tl in &self.top_levels {
             match tl {
-                TopLevel::Stmt(Stmt::Assignment(ident, expr)) => {
-                    scope.insert(ident.to_string(), self.eval(expr, &scope, context)?);
+                TopLevel::Stmt(stmt, _) => {
+                    if self.exec_stmt(stmt, scope, env, edits)?.is_some() {
+                        return Err(KybError::ReturnOutsideFunction);
+                    }
                 }
-
-                TopLevel::Stmt(Stmt::Expr(e)) => match self.eval(e, &mut scope, context)? {
-                    Value::Mutations(m) => result.extend(m),
-                    _ => {}
-                },
+                TopLevel::Import(import) => self.import(import, scope, env)?,
                 TopLevel::Directive(_) => {}
-                unhandled => {
-                    unimplemented!("unhandled: {:?}", unhandled);
+                TopLevel::Function(f) => {
+                    let closure = Closure {
+                        function: f.clone(),
+                        scope: scope.clone(),
+                    };
+                    scope.insert(f.name.clone(), Value::Function(Rc::new(closure)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn import(
+        &self,
+        import: &Import,
+        scope: &mut HashMap<String, Value>,
+        env: &Env,
+    ) -> Result<(), KybError> {
+        let module = self
+            .modules
+            .get(&import.source)
+            .ok_or_else(|| KybError::UnresolvedImport(import.source.clone()))?;
+
+        let mut module_scope = HashMap::new();
+        module.exec_top_levels(&mut module_scope, env, &mut Vec::new())?;
+
+        for ident in &import.idents {
+            let value = module_scope
+                .remove(ident)
+                .ok_or_else(|| KybError::UnknownVariable(ident.clone()))?;
+            scope.insert(ident.to_string(), value);
+        }
+
+        Ok(())
+    }
+
+    /// Runs `stmt`, returning the value of the `return` it reached, if any.
+    fn exec_stmt(
+        &self,
+        stmt: &Stmt,
+        scope: &mut HashMap<String, Value>,
+        env: &Env,
+        edits: &mut Vec<Edit>,
+    ) -> Result<Option<Value>, KybError> {
+        match stmt {
+            Stmt::Assignment(ident, expr) => {
+                let value = self.eval(expr, scope, env)?;
+                scope.insert(ident.to_string(), value);
+            }
+
+            Stmt::Expr(e) => {
+                if let Value::Edits(e) = self.eval(e, scope, env)? {
+                    edits.extend(e);
+                }
+            }
+
+            Stmt::ForLoop(ident, expr, body) => {
+                let values = match self.eval(expr, scope, env)? {
+                    Value::List(values) => values,
+                    unexpected => return Err(unexpected.type_error("list")),
+                };
+
+                for value in values {
+                    let mut scope = scope.clone();
+                    scope.insert(ident.to_string(), value);
+                    if let Some(returned) = self.exec_block(body, scope, env, edits)? {
+                        return Ok(Some(returned));
+                    }
                 }
             }
+
+            Stmt::If(condition, body, else_body) => {
+                let body = if self.eval(condition, scope, env)?.into_bool()? {
+                    body
+                } else {
+                    else_body
+                };
+                return self.exec_block(body, scope.clone(), env, edits);
+            }
+
+            Stmt::Return(e, _) => return Ok(Some(self.eval(e, scope, env)?)),
+        }
+
+        Ok(None)
+    }
+
+    fn exec_block(
+        &self,
+        body: &[Stmt],
+        mut scope: HashMap<String, Value>,
+        env: &Env,
+        edits: &mut Vec<Edit>,
+    ) -> Result<Option<Value>, KybError> {
+        for stmt in body {
+            if let Some(returned) = self.exec_stmt(stmt, &mut scope, env, edits)? {
+                return Ok(Some(returned));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls a function declared with `fn`.
+    ///
+    /// Edits made by statements in the body are part of the result, so a function that makes
+    /// edits can only return more edits.
+    fn call(
+        &self,
+        closure: &Rc<Closure>,
+        args: &[Expr],
+        scope: &HashMap<String, Value>,
+        env: &Env,
+    ) -> Result<Value, KybError> {
+        let function = &closure.function;
+        if args.len() != function.params.len() {
+            return Err(KybError::BadArguments(format!(
+                "{} takes {} arguments, found {}",
+                function.name,
+                function.params.len(),
+                args.len()
+            )));
         }
 
-        Ok(result)
+        let mut callee_scope = closure.scope.clone();
+        callee_scope.insert(function.name.clone(), Value::Function(closure.clone()));
+        for (param, arg) in function.params.iter().zip(args) {
+            callee_scope.insert(param.clone(), self.eval(arg, scope, env)?);
+        }
+
+        if env.depth.get() == MAX_CALL_DEPTH {
+            return Err(KybError::RecursionLimit(function.name.clone()));
+        }
+        env.depth.set(env.depth.get() + 1);
+        let mut edits = Vec::new();
+        let returned = self.exec_block(&function.body, callee_scope, env, &mut edits);
+        env.depth.set(env.depth.get() - 1);
+
+        match returned? {
+            None => Ok(Value::Edits(edits)),
+            Some(value) if edits.is_empty() => Ok(value),
+            Some(Value::Edits(returned)) => {
+                edits.extend(returned);
+                Ok(Value::Edits(edits))
+            }
+            Some(value) => Err(value.type_error("edits")),
+        }
     }
 
     fn eval(
         &self,
         expr: &Expr,
         scope: &HashMap<String, Value>,
-        context: &EditorContext,
-    ) -> Result<Value, String> {
+        env: &Env,
+    ) -> Result<Value, KybError> {
         match expr {
-            Expr::MethodCall(obj, method, args) => {
-                let obj = self.eval(obj, scope, context)?;
-                match (obj, method.as_str()) {
-                    (Value::Range(r, _), "replace") => {
-                        let replace_with_expr = args
-                            .get(0)
-                            .ok_or_else(|| format!("Too few arguments to Range.replace"))?;
-                        let replace_with = match self.eval(replace_with_expr, scope, context)? {
-                            Value::String(s) => s,
-                            unexpected => {
-                                return Err(format!("Expected string, found {:?}", unexpected))
-                            }
-                        };
-
-                        let selected = selected(&context.contents_ref());
-                        let (deletes, backspaces) = delete_range(r.clone(), selected)
-                            .ok_or_else(|| String::from("Could not mutate range"))?;
-
-                        let mut mutations = Vec::new();
-                        if deletes > 0 {
-                            mutations.push(Mutation::Delete(deletes));
+            Expr::MethodCall(obj, method, args, _) => {
+                let obj = self.eval(obj, scope, env)?;
+                self.method_call(obj, method, args, scope, env)
+            }
+            Expr::FnCall(func, args, _) => match func.as_str() {
+                "find" | "find_selected" => {
+                    let expr = args.first().ok_or_else(|| too_few(func))?;
+                    let accept = match func.as_str() {
+                        "find" => overlaps,
+                        _ => covers,
+                    };
+
+                    self.find_around_selection(expr, scope, env, accept)
+                }
+
+                "find_in_file" => {
+                    let all_contents = all_contents(&env.context.contents_ref());
+
+                    let expr = args.first().ok_or_else(|| too_few(func))?;
+
+                    let pattern = self.pattern(expr, scope, env, buffer_language(env.context))?;
+                    let found = find_all(&pattern, &all_contents);
+                    Ok(Value::List(found.into_iter().map(Value::Range).collect()))
+                }
+
+                "input_string" => {
+                    let key = args.first().ok_or_else(|| too_few(func))?;
+                    let key = self.eval(key, scope, env)?.into_string()?;
+
+                    match env.inputs {
+                        None => Ok(Value::String(String::new())),
+                        Some(inputs) => inputs
+                            .get(&key)
+                            .map(|s| Value::String(s.clone()))
+                            .ok_or(KybError::MissingInput(key)),
+                    }
+                }
+
+                "abort" => {
+                    let reason = match args.first() {
+                        Some(reason) => self.eval(reason, scope, env)?.into_string()?,
+                        None => String::new(),
+                    };
+                    Err(KybError::Aborted(reason))
+                }
+
+                "select" => {
+                    let [range] = exact_args::<1>(func, args)?;
+                    let range = match self.eval(range, scope, env)? {
+                        Value::Range(m) if m.file.is_none() => m.range,
+                        Value::Range(_) => {
+                            return Err(KybError::BadArguments(
+                                "Can only select in the buffer".to_string(),
+                            ))
                         }
-                        if backspaces > 0 {
-                            mutations.push(Mutation::Backspace(backspaces));
+                        unexpected => return Err(unexpected.type_error("range")),
+                    };
+                    env.selection.replace(Some(range));
+                    Ok(Value::Edits(Vec::new()))
+                }
+
+                "cursor_at" => {
+                    let [offset] = exact_args::<1>(func, args)?;
+                    let offset = match self.eval(offset, scope, env)? {
+                        Value::Number(n) => n,
+                        unexpected => return Err(unexpected.type_error("number")),
+                    };
+                    if !all_contents(&env.context.contents_ref()).is_char_boundary(offset) {
+                        return Err(KybError::BadArguments(format!(
+                            "Offset {} is not in the file",
+                            offset
+                        )));
+                    }
+                    env.selection.replace(Some(offset..offset));
+                    Ok(Value::Edits(Vec::new()))
+                }
+
+                "files" => {
+                    let [glob] = exact_args::<1>(func, args)?;
+                    let glob = self.eval(glob, scope, env)?.into_string()?;
+                    let root = env
+                        .context
+                        .workspace_root
+                        .as_ref()
+                        .ok_or(KybError::NoWorkspace)?;
+
+                    // Suggestions only look at the buffer, rather than reading the whole
+                    // workspace every time the editor asks for them.
+                    if env.inputs.is_none() {
+                        return Ok(Value::List(Vec::new()));
+                    }
+
+                    // The buffer is searched with `find_in_file`, and may not be saved.
+                    let buffer = env.context.file_path.as_ref().map(Path::new);
+                    let files = workspace::files(Path::new(root), &glob, buffer)
+                        .map_err(|e| KybError::Workspace(e.to_string()))?;
+                    Ok(Value::List(
+                        files.into_iter().map(|f| Value::File(Rc::new(f))).collect(),
+                    ))
+                }
+
+                "balanced" | "node" => Err(KybError::PatternOutsideFind),
+
+                name => match scope.get(name) {
+                    Some(Value::Function(closure)) => self.call(closure, args, scope, env),
+                    _ => Err(KybError::UnknownFunction(name.to_string())),
+                },
+            },
+            Expr::Ident(i, _) => scope
+                .get(i)
+                .cloned()
+                .ok_or_else(|| KybError::UnknownVariable(i.clone())),
+            Expr::StringLiteral(s) => Ok(Value::String(s.clone())),
+            Expr::Regex(re) => Ok(Value::Regex(re.clone())),
+            Expr::Bool(b) => Ok(Value::Bool(*b)),
+            Expr::Number(n) => Ok(Value::Number(*n)),
+
+            Expr::List(items) => Ok(Value::List(
+                items
+                    .iter()
+                    .map(|e| self.eval(e, scope, env))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Expr::Map(entries) => {
+                let mut map = HashMap::new();
+                for (key, value) in entries {
+                    let key = self.eval(key, scope, env)?.into_string()?;
+                    map.insert(key, self.eval(value, scope, env)?);
+                }
+                Ok(Value::Map(map))
+            }
+
+            Expr::Index(obj, index, _) => {
+                match (self.eval(obj, scope, env)?, self.eval(index, scope, env)?) {
+                    (Value::List(mut values), Value::Number(i)) => {
+                        if i < values.len() {
+                            Ok(values.swap_remove(i))
+                        } else {
+                            Err(KybError::OutOfBounds {
+                                index: i,
+                                len: values.len(),
+                            })
                         }
-                        mutations.push(Mutation::Insert(replace_with));
-                        Ok(Value::Mutations(mutations))
                     }
-                    unhandled => {
-                        unimplemented!("unhandled: {:?}", unhandled);
+                    (Value::List(_), index) => Err(index.type_error("number")),
+                    (Value::Map(mut map), key) => {
+                        let key = key.into_string()?;
+                        map.remove(&key).ok_or(KybError::MissingKey(key))
+                    }
+                    (obj, _) => Err(obj.type_error("list or map")),
+                }
+            }
+
+            Expr::Equal(left, right) | Expr::NotEqual(left, right) => {
+                let equal = match (self.eval(left, scope, env)?, self.eval(right, scope, env)?) {
+                    (Value::Bool(left), Value::Bool(right)) => left == right,
+                    (Value::Number(left), Value::Number(right)) => left == right,
+                    (left, right) => left.into_string()? == right.into_string()?,
+                };
+                Ok(Value::Bool(equal == matches!(expr, Expr::Equal(_, _))))
+            }
+
+            Expr::And(left, right) => Ok(Value::Bool(
+                self.eval(left, scope, env)?.into_bool()?
+                    && self.eval(right, scope, env)?.into_bool()?,
+            )),
+            Expr::Or(left, right) => Ok(Value::Bool(
+                self.eval(left, scope, env)?.into_bool()?
+                    || self.eval(right, scope, env)?.into_bool()?,
+            )),
+            Expr::Not(e) => Ok(Value::Bool(!self.eval(e, scope, env)?.into_bool()?)),
+
+            Expr::Concatenate(left, right) => {
+                let left = self.eval(left, scope, env)?.into_text()?;
+                let right = self.eval(right, scope, env)?.into_text()?;
+                Ok(Value::String(left + &right))
+            }
+
+            Expr::DotAccess(obj, prop) => {
+                let obj = self.eval(obj, scope, env)?;
+                match obj {
+                    Value::Range(m) => match m.bindings.get(prop) {
+                        Some(binding) => Ok(binding.clone()),
+                        None => range_property(&m, prop, env)
+                            .ok_or_else(|| KybError::Unbound(prop.clone())),
+                    },
+                    Value::File(f) => match prop.as_str() {
+                        "path" => Ok(Value::String(f.path.clone())),
+                        "text" => Ok(Value::String(f.text.clone())),
+                        _ => Err(KybError::UnknownProperty {
+                            receiver: "file",
+                            property: prop.clone(),
+                        }),
+                    },
+                    unexpected => Err(unexpected.type_error("range")),
+                }
+            }
+
+            Expr::Binding(_, _) | Expr::Alternation(_, _) | Expr::Repeat(_, _) => {
+                Err(KybError::PatternOutsideFind)
+            }
+        }
+    }
+
+    fn method_call(
+        &self,
+        obj: Value,
+        method: &str,
+        args: &[Expr],
+        scope: &HashMap<String, Value>,
+        env: &Env,
+    ) -> Result<Value, KybError> {
+        match (obj, method, args) {
+            (
+                Value::Range(m),
+                "replace_with" | "delete" | "insert_before" | "insert_after" | "wrap",
+                _,
+            ) => self.range_method(m, method, args, scope, env),
+            (Value::File(f), "find_all", [pattern]) => {
+                let language = syntax::language_of_path(&f.path);
+                let pattern = self.pattern(pattern, scope, env, language)?;
+                let found = find_all(&pattern, &f.text);
+                Ok(Value::List(
+                    found
+                        .into_iter()
+                        .map(|m| Value::Range(m.in_file(&f)))
+                        .collect(),
+                ))
+            }
+            (Value::List(values), "len", []) => Ok(Value::Number(values.len())),
+            (Value::Map(entries), "len", []) => Ok(Value::Number(entries.len())),
+            (obj @ (Value::String(_) | Value::Range(_)), _, _) => {
+                let receiver = obj.describe();
+                self.string_method(receiver, obj.into_string()?, method, args, scope, env)
+            }
+            (obj, _, _) => Err(KybError::UnknownMethod {
+                receiver: obj.describe(),
+                method: method.to_string(),
+            }),
+        }
+    }
+
+    /// Methods that edit around a range.
+    fn range_method(
+        &self,
+        m: Match,
+        method: &str,
+        args: &[Expr],
+        scope: &HashMap<String, Value>,
+        env: &Env,
+    ) -> Result<Value, KybError> {
+        let string = |e: &Expr| self.eval(e, scope, env)?.into_string();
+        let insert = |at: usize, text: String| Edit {
+            range: at..at,
+            text,
+            file: m.file.clone(),
+        };
+
+        let edits = match method {
+            "replace_with" => {
+                let [text] = exact_args(method, args)?;
+                vec![Edit {
+                    range: m.range.clone(),
+                    text: string(text)?,
+                    file: m.file.clone(),
+                }]
+            }
+            "delete" => {
+                let [] = exact_args(method, args)?;
+                vec![Edit {
+                    range: m.range.clone(),
+                    text: String::new(),
+                    file: m.file.clone(),
+                }]
+            }
+            "insert_before" => {
+                let [text] = exact_args(method, args)?;
+                vec![insert(m.range.start, string(text)?)]
+            }
+            "insert_after" => {
+                let [text] = exact_args(method, args)?;
+                vec![insert(m.range.end, string(text)?)]
+            }
+            "wrap" => {
+                let [prefix, suffix] = exact_args(method, args)?;
+                vec![
+                    insert(m.range.start, string(prefix)?),
+                    insert(m.range.end, string(suffix)?),
+                ]
+            }
+            _ => {
+                return Err(KybError::UnknownMethod {
+                    receiver: "range",
+                    method: method.to_string(),
+                })
+            }
+        };
+        Ok(Value::Edits(edits))
+    }
+
+    /// Methods of strings, which ranges share by using their text.
+    fn string_method(
+        &self,
+        receiver: &'static str,
+        s: String,
+        method: &str,
+        args: &[Expr],
+        scope: &HashMap<String, Value>,
+        env: &Env,
+    ) -> Result<Value, KybError> {
+        let transform = |f: fn(&str) -> String| {
+            let [] = exact_args(method, args)?;
+            Ok(Value::String(f(&s)))
+        };
+
+        match method {
+            "to_upper" => transform(str::to_uppercase),
+            "to_lower" => transform(str::to_lowercase),
+            "to_snake_case" => transform(case::snake_case),
+            "to_camel_case" => transform(case::camel_case),
+            "to_pascal_case" => transform(case::pascal_case),
+            "trim" => transform(|s| s.trim().to_string()),
+
+            // In bytes, like offsets of ranges.
+            "len" => {
+                let [] = exact_args(method, args)?;
+                Ok(Value::Number(s.len()))
+            }
+
+            "split" => {
+                let [separator] = exact_args(method, args)?;
+                let pattern = self.pattern(separator, scope, env, buffer_language(env.context))?;
+
+                let mut parts = Vec::new();
+                let mut start = 0;
+                for found in find_all(&pattern, &s) {
+                    if found.range.is_empty() {
+                        continue;
+                    }
+                    parts.push(Value::String(s[start..found.range.start].to_string()));
+                    start = found.range.end;
+                }
+                parts.push(Value::String(s[start..].to_string()));
+                Ok(Value::List(parts))
+            }
+
+            "replace" => {
+                let [pattern, with] = exact_args(method, args)?;
+                let pattern = self.pattern(pattern, scope, env, buffer_language(env.context))?;
+                let with = self.eval(with, scope, env)?.into_string()?;
+
+                let mut result = String::new();
+                let mut start = 0;
+                for found in find_all(&pattern, &s) {
+                    result += &s[start..found.range.start];
+                    result += &with;
+                    start = found.range.end;
+                }
+                result += &s[start..];
+                Ok(Value::String(result))
+            }
+
+            _ => Err(KybError::UnknownMethod {
+                receiver,
+                method: method.to_string(),
+            }),
+        }
+    }
+
+    fn find_around_selection(
+        &self,
+        expr: &Expr,
+        scope: &HashMap<String, Value>,
+        env: &Env,
+        accept: fn(&Range<usize>, &Range<usize>) -> bool,
+    ) -> Result<Value, KybError> {
+        let contents = env.context.contents_ref();
+        let all_contents = all_contents(&contents);
+        let selected = selected(&contents);
+        let pattern = self.pattern(expr, scope, env, buffer_language(env.context))?;
+
+        let mut offset = 0;
+        while offset <= all_contents.len() {
+            let found = find_at(&pattern, &all_contents, offset).ok_or(KybError::NotFound)?;
+
+            if found.range.start > selected.end {
+                break;
+            }
+            if accept(&found.range, &selected) {
+                return Ok(Value::Range(found));
+            }
+            offset = if found.range.is_empty() {
+                next_char_boundary(&all_contents, found.range.end)
+            } else {
+                found.range.end
+            };
+        }
+
+        Err(KybError::NotFound)
+    }
+
+    /// The pattern `expr`, to search text of `language` with.
+    fn pattern(
+        &self,
+        expr: &Expr,
+        scope: &HashMap<String, Value>,
+        env: &Env,
+        language: Option<&str>,
+    ) -> Result<Pattern, KybError> {
+        let pattern = Pattern::compile(&self.node(expr, scope, env)?);
+        match language {
+            _ if !pattern.has_syntax() => Ok(pattern),
+            Some(language) if syntax::has_grammar(language) => Ok(pattern),
+            language => Err(KybError::NoGrammar(language.map(str::to_string))),
+        }
+    }
+
+    /// The pattern expression `expr` with every variable and argument evaluated.
+    fn node(
+        &self,
+        expr: &Expr,
+        scope: &HashMap<String, Value>,
+        env: &Env,
+    ) -> Result<Node, KybError> {
+        let node = |e: &Expr| self.node(e, scope, env).map(Box::new);
+
+        Ok(match expr {
+            Expr::StringLiteral(s) => Node::Literal(s.clone()),
+            Expr::Regex(re) => Node::Regex(re.clone()),
+
+            Expr::Concatenate(left, right) => Node::Concatenate(node(left)?, node(right)?),
+            Expr::Alternation(left, right) => Node::Alternation(node(left)?, node(right)?),
+            Expr::Repeat(e, repetition) => Node::Repeat(node(e)?, *repetition),
+            Expr::Binding(ident, e) => Node::Binding(ident.to_string(), node(e)?),
+
+            Expr::FnCall(func, args, _) if func == "balanced" => {
+                let open = args.first().ok_or_else(|| too_few(func))?;
+                let open = self.eval(open, scope, env)?.into_string()?;
+                let close = match args.get(1) {
+                    Some(close) => self.eval(close, scope, env)?.into_string()?,
+                    None => match open.as_str() {
+                        "(" => ")",
+                        "[" => "]",
+                        "{" => "}",
+                        "<" => ">",
+                        _ => {
+                            return Err(KybError::BadArguments(format!(
+                                "No default closing delimiter for {:?}",
+                                open
+                            )))
+                        }
                     }
+                    .to_string(),
+                };
+                if open.is_empty() || close.is_empty() || open == close {
+                    return Err(KybError::BadArguments(format!(
+                        "Invalid delimiters {:?} and {:?}",
+                        open, close
+                    )));
+                }
+
+                Node::Balanced(open, close)
+            }
+
+            Expr::FnCall(func, args, _) if func == "node" => {
+                let [kind] = exact_args(func, args)?;
+                Node::Syntax(self.eval(kind, scope, env)?.into_string()?)
+            }
+
+            Expr::Ident(_, _) | Expr::DotAccess(_, _) => match self.eval(expr, scope, env)? {
+                Value::Regex(re) => Node::Regex(re),
+                value => Node::Literal(value.into_string()?),
+            },
+
+            _ => {
+                return Err(KybError::Type {
+                    expected: "pattern",
+                    found: self.eval(expr, scope, env)?.describe(),
+                })
+            }
+        })
+    }
+}
+
+struct Env<'a> {
+    context: &'a EditorContext,
+    /// `None` while suggesting, before the user has been asked for any input.
+    inputs: Option<&'a HashMap<String, String>>,
+    /// Function calls in progress.
+    depth: Cell<usize>,
+    /// What to leave selected once the edits are made, from the last `select` or `cursor_at`.
+    selection: RefCell<Option<Range<usize>>>,
+}
+
+impl<'a> Env<'a> {
+    fn new(context: &'a EditorContext, inputs: Option<&'a HashMap<String, String>>) -> Self {
+        Env {
+            context,
+            inputs,
+            depth: Cell::new(0),
+            selection: RefCell::new(None),
+        }
+    }
+}
+
+fn stmt_inputs(stmt: &Stmt, inputs: &mut Vec<Input>) {
+    match stmt {
+        Stmt::Assignment(_, e) | Stmt::Expr(e) | Stmt::Return(e, _) => expr_inputs(e, inputs),
+        Stmt::ForLoop(_, e, body) => {
+            expr_inputs(e, inputs);
+            for stmt in body {
+                stmt_inputs(stmt, inputs);
+            }
+        }
+        Stmt::If(condition, body, else_body) => {
+            expr_inputs(condition, inputs);
+            for stmt in body.iter().chain(else_body) {
+                stmt_inputs(stmt, inputs);
+            }
+        }
+    }
+}
+
+fn expr_inputs(expr: &Expr, inputs: &mut Vec<Input>) {
+    match expr {
+        Expr::FnCall(func, args, _) => {
+            if let ("input_string", [Expr::StringLiteral(key), Expr::StringLiteral(prompt), ..]) =
+                (func.as_str(), args.as_slice())
+            {
+                if !inputs.iter().any(|i| &i.key == key) {
+                    inputs.push(Input {
+                        key: key.clone(),
+                        prompt: prompt.clone(),
+                        type_: InputType::String,
+                    });
                 }
             }
-            Expr::FnCall(func, args) => match func.as_str() {
-                "find" => {
-                    let contents = context.contents_ref();
-                    let all_contents = contents.iter().map(|r| r.text).collect::<String>();
-                    let selected = selected(&contents);
+            for arg in args {
+                expr_inputs(arg, inputs);
+            }
+        }
+        Expr::MethodCall(obj, _, args, _) => {
+            expr_inputs(obj, inputs);
+            for arg in args {
+                expr_inputs(arg, inputs);
+            }
+        }
+        Expr::Binding(_, e) | Expr::DotAccess(e, _) | Expr::Not(e) => expr_inputs(e, inputs),
+        Expr::Repeat(e, _) => expr_inputs(e, inputs),
+        Expr::List(items) => {
+            for item in items {
+                expr_inputs(item, inputs);
+            }
+        }
+        Expr::Map(entries) => {
+            for (key, value) in entries {
+                expr_inputs(key, inputs);
+                expr_inputs(value, inputs);
+            }
+        }
+        Expr::Index(left, right, _)
+        | Expr::Concatenate(left, right)
+        | Expr::Alternation(left, right)
+        | Expr::Equal(left, right)
+        | Expr::NotEqual(left, right)
+        | Expr::And(left, right)
+        | Expr::Or(left, right) => {
+            expr_inputs(left, inputs);
+            expr_inputs(right, inputs);
+        }
+        Expr::Ident(_, _)
+        | Expr::StringLiteral(_)
+        | Expr::Regex(_)
+        | Expr::Bool(_)
+        | Expr::Number(_) => {}
+    }
+}
+
+fn stmt_uses_syntax(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Assignment(_, e) | Stmt::Expr(e) | Stmt::Return(e, _) => expr_uses_syntax(e),
+        Stmt::ForLoop(_, e, body) => expr_uses_syntax(e) || body.iter().any(stmt_uses_syntax),
+        Stmt::If(condition, body, else_body) => {
+            expr_uses_syntax(condition) || body.iter().chain(else_body).any(stmt_uses_syntax)
+        }
+    }
+}
+
+fn expr_uses_syntax(expr: &Expr) -> bool {
+    match expr {
+        Expr::FnCall(func, args, _) => func == "node" || args.iter().any(expr_uses_syntax),
+        Expr::MethodCall(obj, _, args, _) => {
+            expr_uses_syntax(obj) || args.iter().any(expr_uses_syntax)
+        }
+        Expr::Binding(_, e) | Expr::DotAccess(e, _) | Expr::Not(e) => expr_uses_syntax(e),
+        Expr::Repeat(e, _) => expr_uses_syntax(e),
+        Expr::List(items) => items.iter().any(expr_uses_syntax),
+        Expr::Map(entries) => entries
+            .iter()
+            .any(|(key, value)| expr_uses_syntax(key) || expr_uses_syntax(value)),
+        Expr::Index(left, right, _)
+        | Expr::Concatenate(left, right)
+        | Expr::Alternation(left, right)
+        | Expr::Equal(left, right)
+        | Expr::NotEqual(left, right)
+        | Expr::And(left, right)
+        | Expr::Or(left, right) => expr_uses_syntax(left) || expr_uses_syntax(right),
+        Expr::Ident(_, _)
+        | Expr::StringLiteral(_)
+        | Expr::Regex(_)
+        | Expr::Bool(_)
+        | Expr::Number(_) => false,
+    }
+}
+
+/// The language of the buffer, which strings taken from it are assumed to be in as well.
+fn buffer_language(context: &EditorContext) -> Option<&str> {
+    context.language_id.as_deref().or_else(|| {
+        context
+            .file_path
+            .as_deref()
+            .and_then(syntax::language_of_path)
+    })
+}
+
+/// Leftmost match of `pattern` in `contents` at or after `start`.
+fn find_at(pattern: &Pattern, contents: &str, start: usize) -> Option<Match> {
+    let found = pattern.find_at(contents, start)?;
+
+    let mut m = Match::new(contents, found.range);
+    for name in pattern.repeated() {
+        m.bindings.insert(name.clone(), Value::List(Vec::new()));
+    }
+    for (name, range) in found.bindings {
+        let binding = Match::new(contents, range);
+        match m.bindings.get_mut(&name) {
+            Some(Value::List(list)) => list.push(Value::Range(binding)),
+            _ => {
+                m.bindings.insert(name, Value::Range(binding));
+            }
+        }
+    }
+    Some(m)
+}
+
+/// A property of a range that isn't one of its bindings.
+///
+/// Like every number scripts see, these count UTF-8 bytes, whatever the editor's position
+/// encoding. `start` and `end` are offsets into the buffer, or the file the range was found in.
+/// `line` and `column` are where it starts, both counted from 0.
+fn range_property(m: &Match, prop: &str, env: &Env) -> Option<Value> {
+    let position = || {
+        let contents = match &m.file {
+            Some(f) => f.text.clone(),
+            None => all_contents(&env.context.contents_ref()),
+        };
+        let before = &contents[..m.range.start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (before.matches('\n').count(), before.len() - line_start)
+    };
+
+    Some(match prop {
+        "text" => Value::String(m.text.clone()),
+        "start" => Value::Number(m.range.start),
+        "end" => Value::Number(m.range.end),
+        "line" => Value::Number(position().0),
+        "column" => Value::Number(position().1),
+        _ => return None,
+    })
+}
+
+/// Every non-overlapping match of `pattern` in `contents`, from left to right.
+fn find_all(pattern: &Pattern, contents: &str) -> Vec<Match> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while offset <= contents.len() {
+        let m = match find_at(pattern, contents, offset) {
+            Some(m) => m,
+            None => break,
+        };
+
+        offset = if m.range.is_empty() {
+            next_char_boundary(contents, m.range.end)
+        } else {
+            m.range.end
+        };
+        found.push(m);
+    }
+    found
+}
+
+fn too_few(func: &str) -> KybError {
+    KybError::BadArguments(format!("Too few arguments to {}", func))
+}
+
+fn exact_args<'e, const N: usize>(
+    method: &str,
+    args: &'e [Expr],
+) -> Result<&'e [Expr; N], KybError> {
+    args.try_into().map_err(|_| {
+        KybError::BadArguments(format!(
+            "{} takes {} arguments, found {}",
+            method,
+            N,
+            args.len()
+        ))
+    })
+}
+
+/// A region of the buffer, or of another file, matched by a pattern.
+#[derive(Debug, Clone)]
+struct Match {
+    range: Range<usize>,
+    text: String,
+    /// Matched sub-regions by name, as a list of ranges when bound inside a repetition.
+    bindings: HashMap<String, Value>,
+    /// `None` for the buffer.
+    file: Option<Rc<WorkspaceFile>>,
+}
+
+impl Match {
+    fn new(contents: &str, range: Range<usize>) -> Self {
+        Match {
+            text: contents[range.clone()].to_string(),
+            range,
+            bindings: HashMap::new(),
+            file: None,
+        }
+    }
+
+    /// The match, with its bindings, as a region of `file`.
+    fn in_file(mut self, file: &Rc<WorkspaceFile>) -> Self {
+        fn value_in_file(value: Value, file: &Rc<WorkspaceFile>) -> Value {
+            match value {
+                Value::Range(m) => Value::Range(m.in_file(file)),
+                Value::List(values) => {
+                    Value::List(values.into_iter().map(|v| value_in_file(v, file)).collect())
+                }
+                value => value,
+            }
+        }
+
+        self.file = Some(file.clone());
+        self.bindings = self
+            .bindings
+            .into_iter()
+            .map(|(name, value)| (name, value_in_file(value, file)))
+            .collect();
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Range(Match),
+    Edits(Vec<Edit>),
+    String(String),
+    Regex(regex::Regex),
+    Bool(bool),
+    Number(usize),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+    Function(Rc<Closure>),
+    File(Rc<WorkspaceFile>),
+}
+
+/// A function with the top level scope it was declared in.
+struct Closure {
+    function: Rc<Function>,
+    scope: HashMap<String, Value>,
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fn {}", self.function.name)
+    }
+}
+
+impl Value {
+    fn into_string(self) -> Result<String, KybError> {
+        match self {
+            Value::String(s) => Ok(s),
+            Value::Range(m) => Ok(m.text),
+            unexpected => Err(unexpected.type_error("string")),
+        }
+    }
+
+    /// Like `into_string`, but also writes numbers in decimal, for concatenation.
+    fn into_text(self) -> Result<String, KybError> {
+        match self {
+            Value::Number(n) => Ok(n.to_string()),
+            value => value.into_string(),
+        }
+    }
+
+    fn into_bool(self) -> Result<bool, KybError> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            unexpected => Err(unexpected.type_error("bool")),
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::Range(_) => "range",
+            Value::Edits(_) => "edits",
+            Value::String(_) => "string",
+            Value::Regex(_) => "regex",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+            Value::File(_) => "file",
+        }
+    }
+
+    fn type_error(&self, expected: &'static str) -> KybError {
+        KybError::Type {
+            expected,
+            found: self.describe(),
+        }
+    }
+}
+
+/// Replacement of a range of the full buffer contents, or of another file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Edit {
+    range: Range<usize>,
+    text: String,
+    /// `None` for the buffer.
+    file: Option<Rc<WorkspaceFile>>,
+}
+
+fn all_contents(contents: &[ContentRegion<&str>]) -> String {
+    contents.iter().map(|r| r.text).collect()
+}
+
+fn selected(contents: &[ContentRegion<&str>]) -> Range<usize> {
+    let mut start = 0;
+    for r in contents {
+        if r.selected {
+            let end = start + r.text.len();
+            return start..end;
+        }
+
+        start += r.text.len();
+    }
+    0..0
+}
+
+fn overlaps(larger: &Range<usize>, smaller: &Range<usize>) -> bool {
+    larger.start <= smaller.start && larger.end >= smaller.end && smaller.start < larger.end
+}
+
+/// Keystrokes that apply all of the edits at once.
+///
+/// Keystrokes can only change text around the cursor, so everything between the selection and
+/// the outermost edits is deleted and typed again with the edits applied.
+fn mutations(
+    edits: &[Edit],
+    contents: &[ContentRegion<&str>],
+    encoding: PositionEncoding,
+) -> Option<Vec<Mutation>> {
+    if edits.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let all_contents = all_contents(contents);
+    let selected = selected(contents);
+
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    edits.sort_by_key(|e| (e.range.start, e.range.end));
+
+    let start = edits
+        .iter()
+        .map(|e| e.range.start)
+        .min()?
+        .min(selected.start);
+    let end = edits.iter().map(|e| e.range.end).max()?.max(selected.end);
+
+    let mut insert = String::new();
+    let mut offset = start;
+    for edit in edits {
+        if edit.range.start < offset {
+            return None;
+        }
+        insert += &all_contents[offset..edit.range.start];
+        insert += &edit.text;
+        offset = edit.range.end;
+    }
+    insert += &all_contents[offset..end];
+
+    let (deletes, backspaces) = delete_range(start..end, selected, &all_contents, encoding)?;
+
+    let mut mutations = Vec::new();
+    if deletes > 0 {
+        mutations.push(Mutation::Delete(deletes));
+    }
+    if backspaces > 0 {
+        mutations.push(Mutation::Backspace(backspaces));
+    }
+    mutations.push(Mutation::Insert(insert));
+    Some(mutations)
+}
+
+/// Selects where `selection` ends up once `edits` are made, or puts the cursor there if it is
+/// empty.
+///
+/// Text inserted at the edges of the selection is left out of it, while text inserted where the
+/// cursor is goes before it, as if it was typed.
+/// `edited` is the buffer with the edits made, which the offsets are into.
+fn selection_mutation(
+    selection: Range<usize>,
+    edits: &[Edit],
+    edited: &str,
+    encoding: PositionEncoding,
+) -> Mutation {
+    let moved = moved_selection(selection, edits);
+    let offset = |offset| encoding.len(&edited[..offset]);
+    if moved.is_empty() {
+        Mutation::Cursor(offset(moved.start))
+    } else {
+        Mutation::Select {
+            start: offset(moved.start),
+            end: offset(moved.end),
+        }
+    }
+}
+
+fn moved_selection(selection: Range<usize>, edits: &[Edit]) -> Range<usize> {
+    if selection.is_empty() {
+        let cursor = moved_offset(selection.start, edits, true);
+        return cursor..cursor;
+    }
+
+    moved_offset(selection.start, edits, true)..moved_offset(selection.end, edits, false)
+}
+
+/// `edits` in order, with positions in `coordinates`, or `None` if any of them overlap.
+fn text_edits(
+    edits: &[Edit],
+    selection: Option<Range<usize>>,
+    contents: &[ContentRegion<&str>],
+    coordinates: Coordinates,
+    encoding: PositionEncoding,
+) -> Option<TextEdits> {
+    let all_contents = all_contents(contents);
+    let text_range = |text: &str, range: Range<usize>| TextRange {
+        start: position(text, range.start, coordinates, encoding),
+        end: position(text, range.end, coordinates, encoding),
+    };
+
+    let text_edits = |text: &str, mut edits: Vec<Edit>| {
+        edits.sort_by_key(|e| (e.range.start, e.range.end));
+        if edits.windows(2).any(|w| w[1].range.start < w[0].range.end) {
+            return None;
+        }
+        Some(
+            edits
+                .into_iter()
+                .map(|e| TextEdit {
+                    range: text_range(text, e.range),
+                    new_text: e.text,
+                })
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    let mut buffer_edits = Vec::new();
+    let mut file_edits = BTreeMap::<_, (Rc<WorkspaceFile>, Vec<Edit>)>::new();
+    for edit in edits {
+        match &edit.file {
+            None => buffer_edits.push(edit.clone()),
+            Some(file) => file_edits
+                .entry(file.full_path.display().to_string())
+                .or_insert_with(|| (file.clone(), Vec::new()))
+                .1
+                .push(edit.clone()),
+        }
+    }
+
+    let converted = text_edits(&all_contents, buffer_edits.clone())?;
+    let selection = selection.map(|selection| {
+        text_range(
+            &edited(&all_contents, &buffer_edits),
+            moved_selection(selection, &buffer_edits),
+        )
+    });
+
+    let mut files = BTreeMap::new();
+    for (path, (file, edits)) in file_edits {
+        files.insert(path, text_edits(&file.text, edits)?);
+    }
+
+    Some(TextEdits {
+        edits: converted,
+        selection,
+        files,
+    })
+}
+
+fn position(
+    text: &str,
+    offset: usize,
+    coordinates: Coordinates,
+    encoding: PositionEncoding,
+) -> Position {
+    let before = &text[..offset];
+    match coordinates {
+        Coordinates::Offset => Position::Offset(encoding.len(before)),
+        Coordinates::LineColumn => {
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            Position::LineColumn {
+                line: before.matches('\n').count(),
+                column: encoding.len(&before[line_start..]),
+            }
+        }
+    }
+}
+
+/// `contents` with `edits` made, which must not overlap.
+fn edited(contents: &str, edits: &[Edit]) -> String {
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    edits.sort_by_key(|e| (e.range.start, e.range.end));
+
+    let mut edited = String::new();
+    let mut offset = 0;
+    for edit in edits {
+        edited += &contents[offset..edit.range.start];
+        edited += &edit.text;
+        offset = edit.range.end;
+    }
+    edited += &contents[offset..];
+    edited
+}
+
+/// Where `offset` ends up once `edits` are made.
+///
+/// An offset inside a replaced range moves to the start of the new text, or to its end when
+/// `after_inserts` is set, which also puts it after text inserted right at it.
+fn moved_offset(offset: usize, edits: &[Edit], after_inserts: bool) -> usize {
+    let mut added = 0;
+    let mut removed = 0;
+    for edit in edits {
+        let range = &edit.range;
+        let before =
+            range.end < offset || (range.end == offset && (after_inserts || !range.is_empty()));
+        if before {
+            added += edit.text.len();
+            removed += range.len();
+        } else if range.start < offset {
+            removed += offset - range.start;
+            if after_inserts {
+                added += edit.text.len();
+            }
+        }
+    }
+    offset + added - removed
+}
+
+/// Whether `found` is exactly the selection, or contains the cursor when nothing is selected.
+fn covers(found: &Range<usize>, selected: &Range<usize>) -> bool {
+    if selected.is_empty() {
+        overlaps(found, selected)
+    } else {
+        found == selected
+    }
+}
+
+/// Deletes and backspaces that remove `to_delete` from `contents`, counted in `encoding`.
+fn delete_range(
+    to_delete: Range<usize>,
+    selected: Range<usize>,
+    contents: &str,
+    encoding: PositionEncoding,
+) -> Option<(usize, usize)> {
+    let deletes_needed = encoding.len(contents.get(selected.end..to_delete.end)?);
+    let backspace_needed = encoding.len(contents.get(to_delete.start..selected.start)?);
+
+    let selected_len = selected.end - selected.start;
+    if selected_len > 0 {
+        Some((deletes_needed + 1, backspace_needed))
+    } else {
+        Some((deletes_needed, backspace_needed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{refactorings::parser::parse, ContentRegion};
+
+    fn context(regions: &[&str]) -> EditorContext {
+        EditorContext {
+            contents: regions
+                .iter()
+                .enumerate()
+                .map(|(i, text)| ContentRegion {
+                    text: text.to_string(),
+                    selected: i % 2 == 1,
+                })
+                .collect(),
+            language_id: None,
+            file_path: None,
+            workspace_root: None,
+            position_encoding: PositionEncoding::Utf8,
+        }
+    }
+
+    fn env(context: &EditorContext) -> Env<'_> {
+        Env::new(context, None)
+    }
+
+    /// Keystrokes `script` makes to a buffer of `regions`, without any inputs.
+    fn replacement(script: &str, regions: &[&str]) -> Result<Vec<Mutation>, PerformError> {
+        replacement_in(script, &context(regions))
+    }
+
+    fn replacement_in(
+        script: &str,
+        context: &EditorContext,
+    ) -> Result<Vec<Mutation>, PerformError> {
+        parse(script).unwrap().perform(context, &HashMap::new())
+    }
+
+    #[cfg(test)]
+    mod applies_to {
+        use super::*;
+
+        #[test]
+        fn single_char() {
+            let script = parse(r#"find("t");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["t"])).unwrap());
+            assert!(!script.applies_to(&context(&["u"])).unwrap());
+        }
+
+        #[test]
+        fn anywhere_in_string() {
+            let script = parse(r#"find("test");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["test"])).unwrap());
+            assert!(script.applies_to(&context(&["t", "", "est"])).unwrap());
+            assert!(!script.applies_to(&context(&["test", "", ""])).unwrap());
+            assert!(!script.applies_to(&context(&["r", "", "est"])).unwrap());
+            assert!(!script.applies_to(&context(&["t", "", "t"])).unwrap());
+        }
+
+        #[test]
+        fn with_selected() {
+            let script = parse(r#"find("test");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["t", "es", "t"])).unwrap());
+        }
+
+        #[test]
+        fn concatenated() {
+            let script = parse(r#"find("te" .. "st");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["test"])).unwrap());
+        }
+
+        #[test]
+        fn binding() {
+            let script = parse(r#"find(foo:("te" .. "st"));"#).unwrap();
+
+            assert!(script.applies_to(&context(&["test"])).unwrap());
+        }
+
+        #[test]
+        fn regex() {
+            let script = parse(r#"find(/s+/);"#).unwrap();
+
+            assert!(script.applies_to(&context(&["s"])).unwrap());
+        }
+
+        #[test]
+        fn multiple_tries_for_concat() {
+            let script = parse(r#"find("te" .. "st");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["tet", "", "est"])).unwrap());
+        }
+
+        #[test]
+        fn end_to_end() {
+            let script = parse(
+                r#"find(
+                    a:(/[\w_]+/ .. /\s+/) ..
+                    "!=" ..
+                    b:(/\s+/ .. /[\w_]+/));"#,
+            )
+            .unwrap();
+
+            assert!(!script
+                .applies_to(&context(&["extern crate rand; { not_a_winner != false }"]))
+                .unwrap());
+            assert!(script
+                .applies_to(&context(&["not_a_winner ", "!=", " false"]))
+                .unwrap());
+        }
+
+        #[test]
+        fn multiple_instances() {
+            let script = parse(
+                r#"find(
+                    a:(/[\w_]+/ .. /\s+/) ..
+                    "!=" ..
+                    b:(/\s+/ .. /[\w_]+/));"#,
+            )
+            .unwrap();
+
+            assert!(script
+                .applies_to(&context(&["foo != bar; not_a_winner ", "!=", " false"]))
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn overlaps_() {
+        assert!(overlaps(&(0..4), &(3..3)));
+        assert!(!overlaps(&(0..4), &(4..4)));
+    }
+
+    #[test]
+    fn covers_() {
+        assert!(covers(&(0..4), &(0..4)));
+        assert!(covers(&(0..4), &(2..2)));
+        assert!(!covers(&(0..4), &(1..3)));
+        assert!(!covers(&(0..4), &(0..5)));
+        assert!(!covers(&(0..4), &(4..4)));
+    }
+
+    #[test]
+    fn selected_() {
+        assert_eq!(selected(&context(&["test"]).contents_ref()), 0..0);
+        assert_eq!(selected(&context(&["test", "some"]).contents_ref()), 4..8);
+        assert_eq!(
+            selected(&context(&["test", "some", "stuff"]).contents_ref()),
+            4..8
+        );
+        assert_eq!(
+            selected(&context(&["test", "", "stuff"]).contents_ref()),
+            4..4
+        );
+    }
+
+    #[cfg(test)]
+    mod perform {
+        use super::*;
+
+        #[test]
+        fn single_char_replacement() {
+            let script = parse(r#"let region = find("t"); region.replace_with("r");"#).unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["t"]), &HashMap::new()).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("r".to_string())]
+            );
+        }
+
+        #[test]
+        fn replace_with_concat() {
+            let script =
+                parse(r#"let region = find("t"); region.replace_with("r" .. "e");"#).unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["t"]), &HashMap::new()).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("re".to_string())]
+            );
+        }
+
+        #[test]
+        fn using_binding() {
+            let script =
+                parse(r#"let region = find("r" .. foo:(/\w+/)); region.replace_with(region.foo);"#)
+                    .unwrap();
+
+            assert_eq!(
+                script
+                    .perform(&context(&["rate"]), &HashMap::new())
+                    .unwrap(),
+                vec![Mutation::Delete(4), Mutation::Insert("ate".to_string())]
+            );
+        }
+
+        #[test]
+        fn multiple_occurrences() {
+            let script = parse(r#"let region = find("r"); region.replace_with("t");"#).unwrap();
+
+            assert_eq!(
+                script
+                    .perform(&context(&["rrr", "", "r"]), &HashMap::new())
+                    .unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("t".to_string())]
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod for_loop {
+        use super::*;
+
+        fn ranges(ranges: &[Range<usize>]) -> HashMap<String, Value> {
+            let ranges = ranges
+                .iter()
+                .map(|r| Value::Range(Match::new("abc", r.clone())))
+                .collect();
+            [("regions".to_string(), Value::List(ranges))]
+                .into_iter()
+                .collect()
+        }
+
+        #[test]
+        fn edits_every_region() {
+            let script = parse(r#"for region in regions { region.replace_with("x"); }"#).unwrap();
+
+            assert_eq!(
+                script
+                    .exec_in(ranges(&[0..1, 2..3]), &env(&context(&["abc"])))
+                    .unwrap(),
+                vec![
+                    Edit {
+                        range: 0..1,
+                        text: "x".to_string(),
+                        file: None
+                    },
+                    Edit {
+                        range: 2..3,
+                        text: "x".to_string(),
+                        file: None
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn iteration_scope_does_not_leak() {
+            let script = parse(
+                r#"
+                for region in regions { let text = "x"; }
+                text;"#,
+            )
+            .unwrap();
+
+            assert!(script
+                .exec_in(ranges(&[0..1, 2..3]), &env(&context(&["abc"])))
+                .is_err());
+        }
+
+        #[test]
+        fn requires_ranges() {
+            let script = parse(r#"for region in "abc" {}"#).unwrap();
+
+            assert!(script.exec(&env(&context(&["abc"]))).is_err());
+        }
+    }
+
+    #[cfg(test)]
+    mod bindings {
+        use super::*;
+
+        #[test]
+        fn replace_only_binding() {
+            let script =
+                parse(r#"let region = find("(" .. e:/\w+/ .. ")"); region.e.replace_with("b");"#)
+                    .unwrap();
+
+            assert_eq!(
+                script.exec(&env(&context(&["(", "", "a)"]))).unwrap(),
+                vec![Edit {
+                    range: 1..2,
+                    text: "b".to_string(),
+                    file: None,
+                }]
+            );
+            assert_eq!(
+                script
+                    .perform(&context(&["(", "", "a)"]), &HashMap::new())
+                    .unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("b".to_string())]
+            );
+        }
+
+        #[test]
+        fn binding_text() {
+            let script = parse(
+                r#"let region = find(a:/\w+/ .. "=" .. b:/\w+/); region.replace_with(region.b .. "=" .. region.a);"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["x=y"]), &HashMap::new()).unwrap(),
+                vec![Mutation::Delete(3), Mutation::Insert("y=x".to_string())]
+            );
+        }
+
+        #[test]
+        fn regex_named_groups() {
+            let script = parse(
+                r#"let region = find(/(?P<lhs>\w+)\s*!=\s*(?P<rhs>\w+)/); region.replace_with(region.rhs .. " != " .. region.lhs);"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script
+                    .perform(&context(&["a!=", "", "b"]), &HashMap::new())
+                    .unwrap(),
+                vec![
+                    Mutation::Delete(1),
+                    Mutation::Backspace(3),
+                    Mutation::Insert("b != a".to_string())
+                ]
+            );
+        }
+
+        #[test]
+        fn unmatched_regex_group() {
+            let script =
+                parse(r#"let region = find(/a(?P<b>b)?/); region.replace_with(region.b);"#)
+                    .unwrap();
+
+            assert!(script.applies_to(&context(&["ab"])).unwrap());
+            assert!(!script.applies_to(&context(&["ac"])).unwrap());
+        }
+
+        #[test]
+        fn range_of_each_binding() {
+            let script = parse(r#"let region = find(a:/\w+/ .. "=" .. b:/\w+/);"#).unwrap();
+            let mut scope = HashMap::new();
+            for tl in &script.top_levels {
+                if let TopLevel::Stmt(stmt, _) = tl {
+                    script
+                        .exec_stmt(
+                            stmt,
+                            &mut scope,
+                            &env(&context(&["  ", "", "x=yz"])),
+                            &mut Vec::new(),
+                        )
+                        .unwrap();
+                }
+            }
+
+            let region = match &scope["region"] {
+                Value::Range(m) => m,
+                unexpected => panic!("Expected range, found {:?}", unexpected),
+            };
+            assert_eq!(region.range, 2..6);
+            let binding = |name: &str| match &region.bindings[name] {
+                Value::Range(m) => m.clone(),
+                unexpected => panic!("Expected range, found {:?}", unexpected),
+            };
+            assert_eq!(binding("a").range, 2..3);
+            assert_eq!(binding("b").range, 4..6);
+            assert_eq!(binding("b").text, "yz");
+        }
+    }
+
+    #[cfg(test)]
+    mod combinators {
+        use super::*;
+
+        #[test]
+        fn alternation() {
+            let script = r#"let region = find(a:/\w+/ .. op:(" == " | " != ") .. b:/\w+/);
+                region.op.replace_with(" = ");"#;
+
+            assert_eq!(
+                replacement(script, &["x =", "", "= y"]).unwrap(),
+                vec![
+                    Mutation::Delete(2),
+                    Mutation::Backspace(2),
+                    Mutation::Insert(" = ".to_string())
+                ]
+            );
+            assert_eq!(
+                replacement(script, &["x !", "", "= y"]).unwrap(),
+                vec![
+                    Mutation::Delete(2),
+                    Mutation::Backspace(2),
+                    Mutation::Insert(" = ".to_string())
+                ]
+            );
+        }
+
+        #[test]
+        fn leftmost_alternative() {
+            let script = r#"for region in find_in_file("b" | "a") { region.replace_with("x"); }"#;
+
+            assert_eq!(
+                replacement(script, &["ab"]).unwrap(),
+                vec![Mutation::Delete(2), Mutation::Insert("xx".to_string())]
+            );
+        }
+
+        #[test]
+        fn optional() {
+            let script = r#"let region = find("a" .. "b"?); region.replace_with("x");"#;
+
+            assert_eq!(
+                replacement(script, &["abc"]).unwrap(),
+                vec![Mutation::Delete(2), Mutation::Insert("x".to_string())]
+            );
+            assert_eq!(
+                replacement(script, &["acb"]).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("x".to_string())]
+            );
+        }
+
+        #[test]
+        fn repetition_bindings() {
+            let script = r#"
+                let region = find("f(" .. (arg:/\w+/ .. ", "?)* .. ")");
+                for arg in region.arg { arg.replace_with("_"); }"#;
+
+            assert_eq!(
+                replacement(script, &["f(a, bc, d)"]).unwrap(),
+                vec![
+                    Mutation::Delete(10),
+                    Mutation::Insert("f(_, _, _".to_string())
+                ]
+            );
+            assert_eq!(replacement(script, &["f()"]).unwrap(), vec![]);
+        }
+
+        #[test]
+        fn one_or_more() {
+            let script = parse(r#"find("f(" .. /\w/+ .. ")");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["f(abc)"])).unwrap());
+            assert!(!script.applies_to(&context(&["f()"])).unwrap());
+        }
+    }
+
+    #[cfg(test)]
+    mod balanced {
+        use super::*;
+
+        #[test]
+        fn nested() {
+            let script = parse(
+                r#"let region = find("(" .. inner:balanced("(", ")") .. ")");
+                region.replace_with(region.inner);"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script
+                    .perform(&context(&["((a) ", "", "+ b)"]), &HashMap::new())
+                    .unwrap(),
+                vec![
+                    Mutation::Delete(4),
+                    Mutation::Backspace(5),
+                    Mutation::Insert("(a) + b".to_string())
+                ]
+            );
+        }
+
+        #[test]
+        fn default_close() {
+            let script = parse(r#"find("[" .. balanced("[") .. "]");"#).unwrap();
+
+            assert!(script.applies_to(&context(&["a[", "", "b[0]]"])).unwrap());
+            assert!(!script.applies_to(&context(&["a[", "", "b[0]"])).unwrap());
+        }
+
+        #[test]
+        fn invalid_delimiters() {
+            let script = parse(r#"find(balanced("|"));"#).unwrap();
+            assert!(matches!(
+                script.applies_to(&context(&["|", "", "a|"])),
+                Err(KybError::BadArguments(_))
+            ));
+
+            let script = parse(r#"find(balanced("|", "|"));"#).unwrap();
+            assert!(matches!(
+                script.applies_to(&context(&["|", "", "a|"])),
+                Err(KybError::BadArguments(_))
+            ));
+        }
+    }
+
+    #[cfg(test)]
+    mod string_methods {
+        use super::*;
+
+        fn value(expr: &str, text: &str) -> Value {
+            let script = parse(&format!("let value = {};", expr)).unwrap();
+            let mut scope = HashMap::new();
+            script
+                .exec_top_levels(&mut scope, &env(&context(&[text])), &mut Vec::new())
+                .unwrap();
+            scope.remove("value").unwrap()
+        }
+
+        fn string(expr: &str, text: &str) -> String {
+            value(expr, text).into_string().unwrap()
+        }
+
+        #[test]
+        fn case() {
+            assert_eq!(string(r#""fooBar".to_upper()"#, ""), "FOOBAR");
+            assert_eq!(string(r#""FooBar".to_lower()"#, ""), "foobar");
+            assert_eq!(string(r#""fooBar".to_snake_case()"#, ""), "foo_bar");
+            assert_eq!(string(r#""foo_bar".to_camel_case()"#, ""), "fooBar");
+            assert_eq!(string(r#""foo_bar".to_pascal_case()"#, ""), "FooBar");
+            assert_eq!(
+                string(r#""fooBar".to_snake_case().to_upper()"#, ""),
+                "FOO_BAR"
+            );
+        }
+
+        #[test]
+        fn on_ranges() {
+            assert_eq!(string(r#"find(/\w+/).to_upper()"#, "abc"), "ABC");
+            assert_eq!(string(r#"find(/\w+/).trim()"#, "abc"), "abc");
+        }
+
+        #[test]
+        fn trim() {
+            assert_eq!(string(r#"" a b\n".trim()"#, ""), "a b");
+        }
+
+        #[test]
+        fn len_counts_chars() {
+            assert!(matches!(value(r#""héllo".len()"#, ""), Value::Number(6)));
+            assert!(matches!(
+                value(r#""abc".len() == 3"#, ""),
+                Value::Bool(true)
+            ));
+        }
+
+        #[test]
+        fn replace() {
+            assert_eq!(string(r#""a-b-c".replace("-", "_")"#, ""), "a_b_c");
+            assert_eq!(string(r#""a1b22".replace(/\d+/, "0")"#, ""), "a0b0");
+            assert_eq!(string(r#""ab".replace(/x*/, "-")"#, ""), "-a-b-");
+        }
+
+        #[test]
+        fn split() {
+            let parts = match value(r#""a, b,c".split(/,\s*/)"#, "") {
+                Value::List(parts) => parts
+                    .into_iter()
+                    .map(|p| p.into_string().unwrap())
+                    .collect::<Vec<_>>(),
+                unexpected => panic!("Expected list, found {:?}", unexpected),
+            };
+            assert_eq!(parts, vec!["a", "b", "c"]);
+        }
+
+        #[test]
+        fn replace_with_transformed_text() {
+            let script = parse(
+                r#"
+                let name = find(/\w+/);
+                name.replace_with(name.to_snake_case().to_upper());"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["fooBar"]), &HashMap::new()),
+                Ok(vec![
+                    Mutation::Delete(6),
+                    Mutation::Insert("FOO_BAR".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn iterate_split() {
+            let script = parse(
+                r#"
+                let region = find(/[\w,]+/);
+                for part in region.split(",") {
+                    if part == "b" { region.replace_with(part); }
+                }"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["a,b"]), &HashMap::new()),
+                Ok(vec![Mutation::Delete(3), Mutation::Insert("b".to_string())])
+            );
+        }
+
+        #[test]
+        fn wrong_arguments() {
+            let script = parse(r#"let r = "a".trim("b");"#).unwrap();
+
+            assert_eq!(
+                script.applies_to(&context(&["a"])),
+                Err(KybError::BadArguments(
+                    "trim takes 0 arguments, found 1".to_string()
+                ))
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod collections {
+        use super::*;
+
+        fn value(expr: &str) -> Result<Value, KybError> {
+            let script = parse(&format!("let value = {};", expr)).unwrap();
+            let mut scope = HashMap::new();
+            script.exec_top_levels(&mut scope, &env(&context(&["a1 b2"])), &mut Vec::new())?;
+            Ok(scope.remove("value").unwrap())
+        }
+
+        fn string(expr: &str) -> String {
+            value(expr).unwrap().into_string().unwrap()
+        }
+
+        #[test]
+        fn list_index() {
+            assert_eq!(string(r#"["a", "b"][1]"#), "b");
+            assert_eq!(string(r#"[["a"], ["b", "c"]][1][0]"#), "b");
+            assert_eq!(
+                value(r#"["a"][1]"#).unwrap_err(),
+                KybError::OutOfBounds { index: 1, len: 1 }
+            );
+        }
+
+        #[test]
+        fn map_index() {
+            assert_eq!(string(r#"{"<": ">=", ">": "<="}[">"]"#), "<=");
+            assert_eq!(string(r#"{"a": "b"}[find("a")]"#), "b");
+            assert_eq!(
+                value(r#"{"a": "b"}["c"]"#).unwrap_err(),
+                KybError::MissingKey("c".to_string())
+            );
+        }
+
+        #[test]
+        fn len() {
+            assert!(matches!(value(r#"[1, 2, 3].len()"#), Ok(Value::Number(3))));
+            assert!(matches!(value(r#"{}.len()"#), Ok(Value::Number(0))));
+            assert!(matches!(
+                value(r#"find_in_file(/\d/).len()"#),
+                Ok(Value::Number(2))
+            ));
+        }
+
+        #[test]
+        fn finders_return_lists() {
+            assert_eq!(string(r#"find_in_file(/\w\d/)[1]"#), "b2");
+            assert_eq!(string(r#"find((c:/\w/ .. /\d/ .. " "?)+).c[1]"#), "b");
+        }
+
+        #[test]
+        fn iterate_literal() {
+            let script = parse(
+                r#"
+                let region = find(/\w+/);
+                for pair in [["a", "x"], ["b", "y"]] {
+                    if region == pair[0] { region.replace_with(pair[1]); }
+                }"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["b"]), &HashMap::new()),
+                Ok(vec![Mutation::Delete(1), Mutation::Insert("y".to_string())])
+            );
+        }
+
+        #[test]
+        fn not_indexable() {
+            assert_eq!(
+                value(r#""a"[0]"#).unwrap_err(),
+                KybError::Type {
+                    expected: "list or map",
+                    found: "string"
+                }
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod range_api {
+        use super::*;
+
+        #[test]
+        fn delete() {
+            assert_eq!(
+                replacement(r#"find(" " .. "mut").delete();"#, &["let m", "", "ut x"]),
+                Ok(vec![
+                    Mutation::Delete(2),
+                    Mutation::Backspace(2),
+                    Mutation::Insert("".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn insert_before() {
+            assert_eq!(
+                replacement(r#"find(/\w+/).insert_before("&");"#, &["f(a", "", "b)"]),
+                Ok(vec![
+                    Mutation::Backspace(1),
+                    Mutation::Insert("&a".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn insert_after_away_from_cursor() {
+            assert_eq!(
+                replacement(
+                    r#"for r in find_in_file("x()") { r.insert_after("?"); }"#,
+                    &["", "", "let a = x();"]
+                ),
+                Ok(vec![
+                    Mutation::Delete(11),
+                    Mutation::Insert("let a = x()?".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn replace_with_replaced_text() {
+            assert_eq!(
+                replacement(
+                    r#"let r = find(/\w+/); r.replace_with(r.replace("a", "o"));"#,
+                    &["", "", "cat"]
+                ),
+                Ok(vec![
+                    Mutation::Delete(3),
+                    Mutation::Insert("cot".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn concatenate_numbers() {
+            assert_eq!(
+                replacement(
+                    r#"let r = find("x"); r.replace_with(r.text .. r.column .. ":" .. r.end);"#,
+                    &["a ", "", "x"]
+                ),
+                Ok(vec![
+                    Mutation::Delete(1),
+                    Mutation::Insert("x2:3".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn wrap() {
+            assert_eq!(
+                replacement(r#"find(/\w+/).wrap("Some(", ")");"#, &["", "", "ab"]),
+                Ok(vec![
+                    Mutation::Delete(2),
+                    Mutation::Insert("Some(ab)".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn multibyte_properties_in_bytes() {
+            let script = parse(
+                r#"
+                let r = find("🦀x");
+                if r.start == 5 && r.line == 1 && r.column == 2 && r.len() == 5 {
+                    r.delete();
+                }"#,
+            )
+            .unwrap();
+
+            for encoding in [PositionEncoding::Utf8, PositionEncoding::Utf16] {
+                let mut context = context(&["é\né", "", "🦀x"]);
+                context.position_encoding = encoding;
+                assert_eq!(script.exec(&env(&context)).unwrap().len(), 1);
+            }
+        }
+
+        #[test]
+        fn properties() {
+            let script = parse(
+                r#"
+                let r = find(/\w+/);
+                if r.start == 5 && r.end == 8 && r.line == 1 && r.column == 3 {
+                    r.replace_with(r.text.to_upper());
+                }"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                script.perform(&context(&["a\né f", "", "oo"]), &HashMap::new()),
+                Ok(vec![
+                    Mutation::Delete(2),
+                    Mutation::Backspace(1),
+                    Mutation::Insert("FOO".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn bindings_shadow_properties() {
+            assert_eq!(
+                replacement(
+                    r#"let r = find(text:/\w/ .. "="); r.replace_with(r.text);"#,
+                    &["a="]
+                ),
+                Ok(vec![Mutation::Delete(2), Mutation::Insert("a".to_string())])
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod text_edits {
+        use super::*;
+
+        fn edits(
+            script: &str,
+            regions: &[&str],
+            coordinates: Coordinates,
+            encoding: PositionEncoding,
+        ) -> TextEdits {
+            let mut context = context(regions);
+            context.position_encoding = encoding;
+            parse(script)
+                .unwrap()
+                .perform_edits(&context, &HashMap::new(), coordinates)
+                .unwrap()
+        }
+
+        fn offsets(start: usize, end: usize) -> TextRange {
+            TextRange {
+                start: Position::Offset(start),
+                end: Position::Offset(end),
+            }
+        }
+
+        const RENAME: &str = r#"for r in find_in_file("é") { r.replace_with("e"); }"#;
+
+        #[test]
+        fn offsets_in_bytes() {
+            assert_eq!(
+                edits(
+                    RENAME,
+                    &["é = 1;\nf(", "", "é);"],
+                    Coordinates::Offset,
+                    PositionEncoding::Utf8
+                )
+                .edits,
+                vec![
+                    TextEdit {
+                        range: offsets(0, 2),
+                        new_text: "e".to_string()
+                    },
+                    TextEdit {
+                        range: offsets(10, 12),
+                        new_text: "e".to_string()
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn offsets_in_chars() {
+            assert_eq!(
+                edits(
+                    RENAME,
+                    &["é = 1;\nf(", "", "é);"],
+                    Coordinates::Offset,
+                    PositionEncoding::Utf32
+                )
+                .edits,
+                vec![
+                    TextEdit {
+                        range: offsets(0, 1),
+                        new_text: "e".to_string()
+                    },
+                    TextEdit {
+                        range: offsets(9, 10),
+                        new_text: "e".to_string()
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn lines_and_columns() {
+            let at = |line, column| Position::LineColumn { line, column };
+            assert_eq!(
+                edits(
+                    RENAME,
+                    &["é = 1;\nf(", "", "é);"],
+                    Coordinates::LineColumn,
+                    PositionEncoding::Utf32
+                )
+                .edits,
+                vec![
+                    TextEdit {
+                        range: TextRange {
+                            start: at(0, 0),
+                            end: at(0, 1)
+                        },
+                        new_text: "e".to_string()
+                    },
+                    TextEdit {
+                        range: TextRange {
+                            start: at(1, 2),
+                            end: at(1, 3)
+                        },
+                        new_text: "e".to_string()
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn selection_in_edited_buffer() {
+            let script = r#"let r = find(/\w+/); r.replace_with("long_name"); select(r);"#;
+            assert_eq!(
+                edits(
+                    script,
+                    &["é + a", "", "b"],
+                    Coordinates::Offset,
+                    PositionEncoding::Utf32
+                ),
+                TextEdits {
+                    edits: vec![TextEdit {
+                        range: offsets(4, 6),
+                        new_text: "long_name".to_string()
+                    }],
+                    selection: Some(offsets(4, 13)),
+                    files: BTreeMap::new(),
+                }
+            );
+        }
+
+        #[test]
+        fn overlapping() {
+            let script = r#"find("ab").replace_with("x"); find(/b/).replace_with("y");"#;
+            assert_eq!(
+                parse(script).unwrap().perform_edits(
+                    &context(&["a", "", "b"]),
+                    &HashMap::new(),
+                    Coordinates::Offset
+                ),
+                Err(PerformError::Failed(KybError::OverlappingEdits))
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod position_encodings {
+        use super::*;
+
+        const EMPTY_STRING: &str = r#"
+            let s = find(/"[^"]*"/);
+            s.replace_with("\"\"");
+            select(s);"#;
+
+        fn encoded(regions: &[&str], encoding: PositionEncoding) -> EditorContext {
+            let mut context = context(regions);
+            context.position_encoding = encoding;
+            context
+        }
+
+        #[test]
+        fn multibyte_identifier() {
+            let script = r#"find(/\w+/).replace_with("coffee");"#;
+            for (encoding, deletes) in [
+                (PositionEncoding::Utf8, 2),
+                (PositionEncoding::Utf16, 1),
+                (PositionEncoding::Utf32, 1),
+            ] {
+                assert_eq!(
+                    replacement_in(script, &encoded(&["let caf", "", "é = 1;"], encoding)).unwrap(),
+                    vec![
+                        Mutation::Delete(deletes),
+                        Mutation::Backspace(3),
+                        Mutation::Insert("coffee".to_string())
+                    ]
+                );
+            }
+        }
+
+        #[test]
+        fn emoji() {
+            for (encoding, deletes, start) in [
+                (PositionEncoding::Utf8, 5, 11),
+                (PositionEncoding::Utf16, 3, 9),
+                (PositionEncoding::Utf32, 2, 8),
+            ] {
+                assert_eq!(
+                    replacement_in(
+                        EMPTY_STRING,
+                        &encoded(&["let 🦀 = \"🦀", "", "🦀\";"], encoding)
+                    )
+                    .unwrap(),
+                    vec![
+                        Mutation::Delete(deletes),
+                        Mutation::Backspace(deletes),
+                        Mutation::Insert("\"\"".to_string()),
+                        Mutation::Select {
+                            start,
+                            end: start + 2
+                        },
+                    ]
+                );
+            }
+        }
+
+        #[test]
+        fn emoji_text_edits() {
+            let mut context = context(&["let 🦀 = \"🦀", "", "🦀\";"]);
+            context.position_encoding = PositionEncoding::Utf16;
+            let at = |line, column| Position::LineColumn { line, column };
+
+            assert_eq!(
+                parse(EMPTY_STRING).unwrap().perform_edits(
+                    &context,
+                    &HashMap::new(),
+                    Coordinates::LineColumn
+                ),
+                Ok(TextEdits {
+                    edits: vec![TextEdit {
+                        range: TextRange {
+                            start: at(0, 9),
+                            end: at(0, 15)
+                        },
+                        new_text: "\"\"".to_string()
+                    }],
+                    selection: Some(TextRange {
+                        start: at(0, 9),
+                        end: at(0, 11)
+                    }),
+                    files: BTreeMap::new(),
+                })
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod availability {
+        use super::*;
+
+        fn available(script: &str, language_id: Option<&str>, file_path: Option<&str>) -> bool {
+            let mut context = context(&[""]);
+            context.language_id = language_id.map(str::to_string);
+            context.file_path = file_path.map(str::to_string);
+            parse(script).unwrap().available_in(&context)
+        }
+
+        #[test]
+        fn languages() {
+            let script = r#"@languages = "rust, toml";"#;
+            assert!(available(script, Some("rust"), None));
+            assert!(available(script, Some("toml"), None));
+            assert!(!available(script, Some("python"), None));
+            assert!(available(script, None, None));
+            assert!(available("", Some("python"), None));
+        }
+
+        #[test]
+        fn files() {
+            let script = r#"@files = "*.rs, src/bin/?.rs";"#;
+            assert!(available(script, None, Some("/home/a/main.rs")));
+            assert!(available(script, None, Some("lib.rs")));
+            assert!(available(script, None, Some(r"C:\a\lib.rs")));
+            assert!(!available(script, None, Some("/a/main.py")));
+            assert!(!available(script, None, Some("/a/main.rs.bak")));
+            assert!(!available(
+                r#"@files = "src/*.py";"#,
+                None,
+                Some("/a/src/b/c.py")
+            ));
+            assert!(available(script, None, None));
+        }
+
+        #[test]
+        fn files_from_workspace_root() {
+            let script = parse(r#"@files = "src/bin/?.rs";"#).unwrap();
+            let mut context = context(&[""]);
+            context.file_path = Some("/a/src/bin/x.rs".to_string());
+            assert!(!script.available_in(&context));
+
+            context.workspace_root = Some("/a".to_string());
+            assert!(script.available_in(&context));
+
+            context.file_path = Some("/a/vendor/src/bin/x.rs".to_string());
+            assert!(!script.available_in(&context));
+        }
+
+        #[test]
+        fn syntax_nodes_need_a_grammar() {
+            let script = r#"fn f() { return find(node("identifier")); } f();"#;
+            assert!(available(script, Some("rust"), None));
+            assert!(available(script, None, Some("/a/main.rs")));
+            assert!(!available(script, None, None));
+            assert!(!available(script, Some("python"), Some("/a/main.rs")));
+            assert!(available(r#"find("a");"#, None, None));
+        }
+    }
+
+    #[cfg(test)]
+    mod syntax_nodes {
+        use super::*;
+
+        fn rust_context(regions: &[&str]) -> EditorContext {
+            let mut context = context(regions);
+            context.language_id = Some("rust".to_string());
+            context
+        }
+
+        #[test]
+        fn fields_are_bindings() {
+            let script = r#"
+                let e = find(node("binary_expression"));
+                e.left.replace_with(e.right.text);
+                e.right.replace_with(e.left.text);"#;
+
+            assert_eq!(
+                replacement_in(script, &rust_context(&["a + f(", "", "x);"])),
+                Ok(vec![
+                    Mutation::Delete(2),
+                    Mutation::Backspace(6),
+                    Mutation::Insert("f(x) + a".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn supertypes() {
+            let script = r#"
+                let e = find(left:node("_expression") .. " == " .. node("_expression"));
+                e.left.replace_with("b");"#;
+
+            assert_eq!(
+                replacement_in(script, &rust_context(&["x.y ", "", "== 1"])),
+                Ok(vec![
+                    Mutation::Backspace(4),
+                    Mutation::Insert("b ".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn not_found() {
+            assert_eq!(
+                replacement_in(
+                    r#"find(node("call_expression"));"#,
+                    &rust_context(&["a ", "", "+ b"])
+                ),
+                Err(PerformError::Failed(KybError::NotFound))
+            );
+        }
+
+        #[test]
+        fn only_in_languages_with_a_grammar() {
+            let script = parse(r#"find(node("binary_expression"));"#).unwrap();
+            let mut context = context(&["a ", "", "+ b"]);
+            let perform = |context: &EditorContext| script.perform(context, &HashMap::new());
+
+            assert_eq!(
+                perform(&context),
+                Err(PerformError::Failed(KybError::NoGrammar(None)))
+            );
+            assert_eq!(script.applies_to(&context), Ok(false));
+
+            context.language_id = Some("python".to_string());
+            assert_eq!(
+                perform(&context),
+                Err(PerformError::Failed(KybError::NoGrammar(Some(
+                    "python".to_string()
+                ))))
+            );
+
+            context.language_id = None;
+            context.file_path = Some("src/main.rs".to_string());
+            assert!(perform(&context).is_ok());
+
+            // Patterns without syntax nodes work in any language.
+            context.file_path = None;
+            let script = parse(r#"find("+");"#).unwrap();
+            assert!(script.perform(&context, &HashMap::new()).is_ok());
+        }
+    }
+
+    #[cfg(test)]
+    mod selections {
+        use super::*;
+
+        #[test]
+        fn select_replacement() {
+            let script = r#"
+                let e = find(/\w+ \+ \w+/);
+                find(/f\(.*\);/).insert_before("let x = " .. e.text .. ";\n");
+                e.replace_with("x");
+                select(e);"#;
+
+            assert_eq!(
+                replacement(script, &["f(a", "", " + b);"]),
+                Ok(vec![
+                    Mutation::Delete(4),
+                    Mutation::Backspace(3),
+                    Mutation::Insert("let x = a + b;\nf(x".to_string()),
+                    Mutation::Select { start: 17, end: 18 },
+                ])
+            );
+        }
+
+        #[test]
+        fn select_leaves_out_insertions() {
+            assert_eq!(
+                replacement(
+                    r#"let r = find(/\w+/); r.wrap("(", ")"); select(r);"#,
+                    &["a", "", "b"]
+                ),
+                Ok(vec![
+                    Mutation::Delete(1),
+                    Mutation::Backspace(1),
+                    Mutation::Insert("(ab)".to_string()),
+                    Mutation::Select { start: 1, end: 3 },
+                ])
+            );
+        }
+
+        #[test]
+        fn select_without_edits() {
+            assert_eq!(
+                replacement(r#"select(find(/\w+/));"#, &["a", "", "b c"]),
+                Ok(vec![Mutation::Select { start: 0, end: 2 }])
+            );
+        }
+
+        #[test]
+        fn cursor_after_insertion() {
+            assert_eq!(
+                replacement(
+                    r#"let r = find(/\w+/); r.insert_after("()"); cursor_at(r.end);"#,
+                    &["fo", "", "o"]
+                ),
+                Ok(vec![
+                    Mutation::Delete(1),
+                    Mutation::Insert("o()".to_string()),
+                    Mutation::Cursor(5),
+                ])
+            );
+        }
+
+        #[test]
+        fn last_one_wins() {
+            assert_eq!(
+                replacement(r#"select(find("a")); cursor_at(0);"#, &["", "", "a"]),
+                Ok(vec![Mutation::Cursor(0)])
+            );
+        }
+
+        #[test]
+        fn cursor_outside_file() {
+            for offset in ["3", "1"] {
+                assert_eq!(
+                    replacement(&format!("cursor_at({});", offset), &["", "", "é"]),
+                    Err(PerformError::Failed(KybError::BadArguments(format!(
+                        "Offset {} is not in the file",
+                        offset
+                    ))))
+                );
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod functions {
+        use super::*;
+
+        #[test]
+        fn returns_value() {
+            let script = r#"
+                fn negate(e) { return "!(" .. e .. ")"; }
+                let region = find(/\w+/);
+                region.replace_with(negate(region));"#;
+
+            assert_eq!(
+                replacement(script, &["a"]),
+                Ok(vec![
+                    Mutation::Delete(1),
+                    Mutation::Insert("!(a)".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn edits_in_body() {
+            let script = r#"
+                fn upper(r) { r.replace_with(r.to_upper()); }
+                upper(find(/\w+/));"#;
+
+            assert_eq!(
+                replacement(script, &["ab"]),
+                Ok(vec![
+                    Mutation::Delete(2),
+                    Mutation::Insert("AB".to_string())
+                ])
+            );
+        }
+
+        #[test]
+        fn return_from_loop() {
+            let script = r#"
+                fn first(parts) {
+                    for part in parts { if part != "" { return part; } }
+                    return "none";
+                }
+                let region = find(/[\w,]+/);
+                region.replace_with(first(region.split(",")));"#;
+
+            assert_eq!(
+                replacement(script, &[",,b,c"]),
+                Ok(vec![Mutation::Delete(5), Mutation::Insert("b".to_string())])
+            );
+        }
+
+        #[test]
+        fn lexical_scope() {
+            let script = r#"
+                let prefix = "x";
+                fn prefixed(s) { return prefix .. s .. suffix; }
+                for suffix in find_in_file("a") { find(prefixed("")); }"#;
+
+            assert_eq!(
+                replacement(script, &["a"]),
+                Err(PerformError::Failed(KybError::UnknownVariable(
+                    "suffix".to_string()
+                )))
+            );
+        }
+
+        #[test]
+        fn recursion_limit() {
+            let script = r#"
+                fn forever(s) { return forever(s); }
+                forever("a");"#;
+
+            assert_eq!(
+                replacement(script, &["a"]),
+                Err(PerformError::Failed(KybError::RecursionLimit(
+                    "forever".to_string()
+                )))
+            );
+        }
 
-                    let expr = args
-                        .get(0)
-                        .ok_or_else(|| String::from("Too few arguments to find"))?;
+        #[test]
+        fn edits_and_value() {
+            let script = r#"
+                fn both(r) { r.replace_with("b"); return "c"; }
+                both(find("a"));"#;
 
-                    let mut offset = 0;
-                    loop {
-                        let (found, bindings) = self.range(expr, &all_contents[offset..])?;
-                        let found = (found.start + offset)..(found.end + offset);
+            assert_eq!(
+                replacement(script, &["a"]),
+                Err(PerformError::Failed(KybError::Type {
+                    expected: "edits",
+                    found: "string"
+                }))
+            );
+        }
+    }
 
-                        if found.start > selected.end {
-                            return Err(format!("Not found"));
-                        }
-                        if overlaps(&found, &selected) {
-                            return Ok(Value::Range(found, bindings));
-                        }
-                        offset = found.end;
-                    }
-                }
+    #[cfg(test)]
+    mod errors {
+        use super::*;
 
-                unhandled => {
-                    unimplemented!("unhandled: {:?}", unhandled);
-                }
-            },
-            Expr::Ident(i) => scope
-                .get(i)
-                .cloned()
-                .ok_or_else(|| format!("Unknown variable {:?}", i)),
-            Expr::StringLiteral(s) => Ok(Value::String(s.clone())),
+        fn applies_to(script: &str, text: &str) -> Result<bool, KybError> {
+            parse(script).unwrap().applies_to(&context(&[text]))
+        }
 
-            Expr::Concatenate(left, right) => {
-                match (
-                    self.eval(left, scope, context)?,
-                    self.eval(right, scope, context)?,
-                ) {
-                    (Value::String(left), Value::String(right)) => Ok(Value::String(left + &right)),
-                    unhandled => {
-                        unimplemented!("unhandled: {:?}", unhandled);
-                    }
-                }
-            }
+        #[test]
+        fn not_applicable() {
+            assert_eq!(applies_to(r#"find("a");"#, "b"), Ok(false));
+            assert_eq!(applies_to(r#"abort("no");"#, "a"), Ok(false));
+            assert_eq!(
+                applies_to(r#"let r = find(/a(?P<b>b)?/); r.replace_with(r.b);"#, "a"),
+                Ok(false)
+            );
+        }
 
-            Expr::DotAccess(obj, prop) => {
-                let obj = self.eval(obj, scope, context)?;
-                match obj {
-                    Value::Range(_, props) => props
-                        .get(prop)
-                        .map(|s| Value::String(s.clone()))
-                        .ok_or_else(|| format!("Region does not have binding {:?}", prop)),
-                    unhandled => {
-                        unimplemented!("unhandled: {:?}", unhandled);
-                    }
-                }
-            }
+        #[test]
+        fn bugs() {
+            assert_eq!(
+                applies_to(r#"fnd("a");"#, "a"),
+                Err(KybError::UnknownFunction("fnd".to_string()))
+            );
+            assert_eq!(
+                applies_to(r#"let r = find("a"); r.remove();"#, "a"),
+                Err(KybError::UnknownMethod {
+                    receiver: "range",
+                    method: "remove".to_string()
+                })
+            );
+            assert_eq!(
+                applies_to(r#"find(x);"#, "a"),
+                Err(KybError::UnknownVariable("x".to_string()))
+            );
+            assert_eq!(
+                applies_to(r#"for r in find("a") {}"#, "a"),
+                Err(KybError::Type {
+                    expected: "list",
+                    found: "range"
+                })
+            );
+            assert_eq!(
+                applies_to(
+                    r#"let r = find("a"); r.replace_with("b"); r.replace_with("c");"#,
+                    "a"
+                ),
+                Err(KybError::OverlappingEdits)
+            );
+        }
 
-            unhandled => {
-                unimplemented!("unhandled: {:?}", unhandled);
-            }
+        #[test]
+        fn serialized_with_message() {
+            assert_eq!(
+                serde_json::to_string(&KybError::UnknownVariable("x".to_string())).unwrap(),
+                r#"{"kind":"unknown_variable","message":"Unknown variable \"x\""}"#
+            );
         }
     }
 
-    fn range(
-        &self,
-        expr: &Expr,
-        contents: &str,
-    ) -> Result<(Range<usize>, HashMap<String, String>), String> {
-        match expr {
-            Expr::StringLiteral(s) => {
-                let start = contents
-                    .find(s)
-                    .ok_or_else(|| format!("Not found {:?}", s))?;
-                let end = start + s.len();
-                Ok((start..end, HashMap::new()))
-            }
-
-            Expr::Regex(re) => {
-                let mat = re
-                    .find(contents)
-                    .ok_or_else(|| format!("No match /{:?}/", re))?;
-                Ok((mat.range(), HashMap::new()))
-            }
+    #[cfg(test)]
+    mod conditionals {
+        use super::*;
 
-            Expr::Concatenate(left, right) => {
-                let (left_range, mut bindings) = self.range(left, contents)?;
+        #[test]
+        fn if_else() {
+            let script = r#"
+                let region = find(/\w+/);
+                if region == "a" {
+                    region.replace_with("x");
+                } else if region != "b" {
+                    region.replace_with("y");
+                } else {
+                    region.replace_with("z");
+                }"#;
 
-                let offset = left_range.end;
-                let rest = &contents[left_range.end..];
-                let (right_range, right_bindings) = self.range(right, rest)?;
+            assert_eq!(
+                replacement(script, &["a"]).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("x".to_string())]
+            );
+            assert_eq!(
+                replacement(script, &["c"]).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("y".to_string())]
+            );
+            assert_eq!(
+                replacement(script, &["b"]).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("z".to_string())]
+            );
+        }
 
-                if right_range.start != 0 {
-                    let (sub, bindings) = self.range(expr, rest)?;
-                    let new_range = (sub.start + offset)..(sub.end + offset);
-                    return Ok((new_range, bindings));
-                }
+        #[test]
+        fn boolean_operators() {
+            let script = r#"
+                let region = find(/\w+/);
+                if !(region == "a" || region == "b") && true {
+                    region.replace_with("x");
+                }"#;
+
+            assert_eq!(replacement(script, &["a"]).unwrap(), vec![]);
+            assert_eq!(
+                replacement(script, &["c"]).unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("x".to_string())]
+            );
+        }
 
-                let start = left_range.start;
-                let end = left_range.end + (right_range.end - right_range.start);
+        #[test]
+        fn block_scope() {
+            let script = r#"
+                if true { let x = "a"; }
+                find(x);"#;
 
-                bindings.extend(right_bindings);
+            assert!(replacement(script, &["a"]).is_err());
+        }
 
-                Ok((start..end, bindings))
-            }
+        #[test]
+        fn condition_must_be_bool() {
+            assert!(replacement(r#"if "a" {}"#, &["a"]).is_err());
+        }
 
-            Expr::Binding(ident, e) => {
-                let (range, mut bindings) = self.range(e, contents)?;
-                bindings.insert(ident.to_string(), contents[range.clone()].to_string());
-                Ok((range, bindings))
-            }
+        #[test]
+        fn abort() {
+            let script = parse(
+                r#"
+                let region = find(/\w+/);
+                if region == "a" { abort("Nothing to do"); }"#,
+            )
+            .unwrap();
 
-            unhandled => {
-                unimplemented!("unhandled: {:?}", unhandled);
-            }
+            assert!(!script.applies_to(&context(&["a"])).unwrap());
+            assert!(script.applies_to(&context(&["b"])).unwrap());
+            assert_eq!(
+                script.perform(&context(&["a"]), &HashMap::new()),
+                Err(PerformError::Failed(KybError::Aborted(
+                    "Nothing to do".to_string()
+                )))
+            );
         }
     }
-}
-
-#[derive(Debug, Clone)]
-enum Value {
-    Range(Range<usize>, HashMap<String, String>),
-    Mutations(Vec<Mutation>),
-    String(String),
-}
 
-fn selected(contents: &[ContentRegion<&str>]) -> Range<usize> {
-    let mut start = 0;
-    for r in contents {
-        if r.selected {
-            let end = start + r.text.len();
-            return start..end;
-        }
+    #[cfg(test)]
+    mod find_selected {
+        use super::*;
 
-        start += r.text.len();
-    }
-    0..0
-}
+        #[test]
+        fn exact_selection() {
+            let script = parse(r#"find_selected(/\w+/);"#).unwrap();
 
-fn overlaps(larger: &Range<usize>, smaller: &Range<usize>) -> bool {
-    larger.start <= smaller.start && larger.end >= smaller.end && smaller.start < larger.end
-}
+            assert!(script.applies_to(&context(&["a ", "foo", " b"])).unwrap());
+            assert!(!script.applies_to(&context(&["a f", "oo", " b"])).unwrap());
+            assert!(!script.applies_to(&context(&["a ", "foo ", "b"])).unwrap());
+        }
 
-fn delete_range(to_delete: Range<usize>, selected: Range<usize>) -> Option<(usize, usize)> {
-    let deletes_needed = to_delete.end.checked_sub(selected.end)?;
-    let backspace_needed = selected.start.checked_sub(to_delete.start)?;
+        #[test]
+        fn cursor_inside() {
+            let script = parse(r#"find_selected(/\w+/);"#).unwrap();
 
-    let selected_len = selected.end - selected.start;
-    if selected_len > 0 {
-        Some((deletes_needed + 1, backspace_needed))
-    } else {
-        Some((deletes_needed, backspace_needed))
-    }
-}
+            assert!(script.applies_to(&context(&["a f", "", "oo b"])).unwrap());
+            assert!(!script.applies_to(&context(&["a foo", "", " b"])).unwrap());
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{refactorings::parser::parse, ContentRegion};
+        #[test]
+        fn later_match() {
+            let script =
+                parse(r#"let found = find_selected(/\w+/); found.replace_with("x");"#).unwrap();
 
-    fn context(regions: &[&str]) -> EditorContext {
-        EditorContext {
-            contents: regions
-                .iter()
-                .enumerate()
-                .map(|(i, text)| ContentRegion {
-                    text: text.to_string(),
-                    selected: i % 2 == 1,
-                })
-                .collect(),
+            assert_eq!(
+                script
+                    .perform(&context(&["a b ", "foo", ""]), &HashMap::new())
+                    .unwrap(),
+                vec![Mutation::Delete(1), Mutation::Insert("x".to_string())]
+            );
         }
     }
 
     #[cfg(test)]
-    mod applies_to {
+    mod inputs {
         use super::*;
 
-        #[test]
-        fn single_char() {
-            let script = parse(r#"find("t");"#).unwrap();
-
-            assert!(script.applies_to(&context(&["t"])));
-            assert!(!script.applies_to(&context(&["u"])));
+        fn inputs(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
         }
 
         #[test]
-        fn anywhere_in_string() {
-            let script = parse(r#"find("test");"#).unwrap();
+        fn listed() {
+            let script = parse(
+                r#"
+                let region = find("t");
+                region.replace_with(input_string("with", "Replace with"));"#,
+            )
+            .unwrap();
 
-            assert!(script.applies_to(&context(&["test"])));
-            assert!(script.applies_to(&context(&["t", "", "est"])));
-            assert!(!script.applies_to(&context(&["test", "", ""])));
-            assert!(!script.applies_to(&context(&["r", "", "est"])));
-            assert!(!script.applies_to(&context(&["t", "", "t"])));
+            assert_eq!(
+                script.inputs(),
+                vec![Input {
+                    key: "with".to_string(),
+                    prompt: "Replace with".to_string(),
+                    type_: InputType::String,
+                }]
+            );
         }
 
         #[test]
-        fn with_selected() {
-            let script = parse(r#"find("test");"#).unwrap();
+        fn missing() {
+            let script = parse(r#"let with = input_string("with", "Replace with");"#).unwrap();
 
-            assert!(script.applies_to(&context(&["t", "es", "t"])));
+            assert_eq!(
+                script.perform(&context(&["t"]), &inputs(&[("other", "r")])),
+                Err(PerformError::MissingInput(Input {
+                    key: "with".to_string(),
+                    prompt: "Replace with".to_string(),
+                    type_: InputType::String,
+                }))
+            );
         }
 
         #[test]
-        fn concatenated() {
-            let script = parse(r#"find("te" .. "st");"#).unwrap();
+        fn supplied() {
+            let script = parse(
+                r#"
+                let region = find("t");
+                region.replace_with(input_string("with", "Replace with"));"#,
+            )
+            .unwrap();
 
-            assert!(script.applies_to(&context(&["test"])));
+            assert_eq!(
+                script.perform(&context(&["t"]), &inputs(&[("with", "r")])),
+                Ok(vec![Mutation::Delete(1), Mutation::Insert("r".to_string())])
+            );
         }
 
         #[test]
-        fn binding() {
-            let script = parse(r#"find(foo:("te" .. "st"));"#).unwrap();
+        fn applies_without_inputs() {
+            let script = parse(
+                r#"
+                let with = input_string("with", "Replace with");
+                let region = find("t");
+                region.replace_with(with);"#,
+            )
+            .unwrap();
 
-            assert!(script.applies_to(&context(&["test"])));
+            assert!(script.applies_to(&context(&["t"])).unwrap());
+            assert!(!script.applies_to(&context(&["u"])).unwrap());
         }
+    }
+
+    #[cfg(test)]
+    mod find_in_file {
+        use super::*;
 
         #[test]
-        fn regex() {
-            let script = parse(r#"find(/s+/);"#).unwrap();
+        fn every_match() {
+            let script =
+                parse(r#"for region in find_in_file("a") { region.replace_with("b"); }"#).unwrap();
 
-            assert!(script.applies_to(&context(&["s"])));
+            assert_eq!(
+                script.perform(&context(&["a a"]), &HashMap::new()).unwrap(),
+                vec![Mutation::Delete(3), Mutation::Insert("b b".to_string())]
+            );
         }
 
         #[test]
-        fn multiple_tries_for_concat() {
-            let script = parse(r#"find("te" .. "st");"#).unwrap();
+        fn non_overlapping() {
+            let script =
+                parse(r#"for region in find_in_file("aa") { region.replace_with("b"); }"#).unwrap();
 
-            assert!(script.applies_to(&context(&["tet", "", "est"])));
+            assert_eq!(script.exec(&env(&context(&["aaa"]))).unwrap().len(), 1);
         }
 
         #[test]
-        fn end_to_end() {
+        fn with_bindings() {
             let script = parse(
-                r#"find(
-                    a:(/[\w_]+/ .. /\s+/) ..
-                    "!=" ..
-                    b:(/\s+/ .. /[\w_]+/));"#,
+                r#"for region in find_in_file("x" .. n:/\d/) { region.replace_with(region.n); }"#,
             )
             .unwrap();
 
-            assert!(!script.applies_to(&context(&["extern crate rand; { not_a_winner != false }"])));
-            assert!(script.applies_to(&context(&["not_a_winner ", "!=", " false"])));
+            assert_eq!(
+                script
+                    .perform(&context(&["x1 x2"]), &HashMap::new())
+                    .unwrap(),
+                vec![Mutation::Delete(5), Mutation::Insert("1 2".to_string())]
+            );
         }
 
         #[test]
-        fn multiple_instances() {
+        fn pattern_from_variables() {
             let script = parse(
-                r#"find(
-                    a:(/[\w_]+/ .. /\s+/) ..
-                    "!=" ..
-                    b:(/\s+/ .. /[\w_]+/));"#,
+                r#"
+                let sep = /,\s*/;
+                let region = find("a");
+                for found in find_in_file(region .. sep) { found.replace_with(""); }"#,
             )
             .unwrap();
 
-            assert!(script.applies_to(&context(&["foo != bar; not_a_winner ", "!=", " false"])));
+            assert_eq!(script.exec(&env(&context(&["a, b,a,"]))).unwrap().len(), 2);
         }
-    }
 
-    #[test]
-    fn overlaps_() {
-        assert!(overlaps(&(0..4), &(3..3)));
-        assert!(!overlaps(&(0..4), &(4..4)));
-    }
+        #[test]
+        fn no_matches() {
+            let script =
+                parse(r#"for region in find_in_file("a") { region.replace_with("b"); }"#).unwrap();
 
-    #[test]
-    fn selected_() {
-        assert_eq!(selected(&context(&["test"]).contents_ref()), 0..0);
-        assert_eq!(selected(&context(&["test", "some"]).contents_ref()), 4..8);
-        assert_eq!(
-            selected(&context(&["test", "some", "stuff"]).contents_ref()),
-            4..8
-        );
-        assert_eq!(
-            selected(&context(&["test", "", "stuff"]).contents_ref()),
-            4..4
-        );
+            assert_eq!(
+                script.perform(&context(&["bbb"]), &HashMap::new()).unwrap(),
+                vec![]
+            );
+        }
     }
 
     #[cfg(test)]
-    mod perform {
+    mod workspace_files {
         use super::*;
+        use crate::refactorings::workspace::tests::workspace;
+
+        const RENAME: &str = r#"
+            let symbol = find_selected(/\w+/);
+            for r in find_in_file(symbol) { r.replace_with("b"); }
+            for f in files("**/*.rs") {
+                for r in f.find_all(symbol) { r.replace_with("b"); }
+            }"#;
+
+        fn context_in(root: &Path, regions: &[&str]) -> EditorContext {
+            let mut context = context(regions);
+            context.file_path = Some(root.join("src/main.rs").display().to_string());
+            context.workspace_root = Some(root.display().to_string());
+            context
+        }
+
+        fn offsets(start: usize, end: usize, new_text: &str) -> TextEdit {
+            TextEdit {
+                range: TextRange {
+                    start: Position::Offset(start),
+                    end: Position::Offset(end),
+                },
+                new_text: new_text.to_string(),
+            }
+        }
 
         #[test]
-        fn single_char_replacement() {
-            let script = parse(r#"let region = find("t"); region.replace("r");"#).unwrap();
+        fn edits_by_file() {
+            let root = workspace(
+                "edits_by_file",
+                &[
+                    ("src/main.rs", "saved a;"),
+                    ("src/lib.rs", "use a; a();"),
+                    ("src/other.rs", "x"),
+                    ("notes.md", "a"),
+                ],
+            );
+            let edits = parse(RENAME)
+                .unwrap()
+                .perform_edits(
+                    &context_in(&root, &["", "a", " = a;"]),
+                    &HashMap::new(),
+                    Coordinates::Offset,
+                )
+                .unwrap();
+
+            assert_eq!(edits.edits, vec![offsets(0, 1, "b"), offsets(4, 5, "b")]);
+            assert_eq!(
+                edits.files,
+                BTreeMap::from([(
+                    root.join("src/lib.rs").display().to_string(),
+                    vec![offsets(4, 5, "b"), offsets(7, 8, "b")]
+                )])
+            );
+        }
 
+        #[test]
+        fn not_as_keystrokes() {
+            let root = workspace("not_as_keystrokes", &[("lib.rs", "a")]);
             assert_eq!(
-                script.perform(&context(&["t"])).unwrap(),
-                vec![Mutation::Delete(1), Mutation::Insert("r".to_string())]
+                parse(RENAME)
+                    .unwrap()
+                    .perform(&context_in(&root, &["", "a", ""]), &HashMap::new()),
+                Err(PerformError::Failed(KybError::EditsToOtherFiles))
             );
         }
 
         #[test]
-        fn replace_with_concat() {
-            let script = parse(r#"let region = find("t"); region.replace("r" .. "e");"#).unwrap();
+        fn without_workspace() {
+            let script = parse(RENAME).unwrap();
+            let context = context(&["", "a", ""]);
 
+            assert_eq!(script.applies_to(&context), Ok(false));
             assert_eq!(
-                script.perform(&context(&["t"])).unwrap(),
-                vec![Mutation::Delete(1), Mutation::Insert("re".to_string())]
+                script.perform_edits(&context, &HashMap::new(), Coordinates::Offset),
+                Err(PerformError::Failed(KybError::NoWorkspace))
             );
         }
 
         #[test]
-        fn using_binding() {
-            let script =
-                parse(r#"let region = find("r" .. foo:(/\w+/)); region.replace(region.foo);"#)
-                    .unwrap();
+        fn not_read_while_suggesting() {
+            let root = workspace("not_read_while_suggesting", &[("lib.rs", "a")]);
+            let script = parse(r#"for f in files("*.rs") { abort("read"); }"#).unwrap();
+            let context = context_in(&root, &[""]);
 
+            assert_eq!(script.applies_to(&context), Ok(true));
             assert_eq!(
-                script.perform(&context(&["rate"])).unwrap(),
-                vec![Mutation::Delete(4), Mutation::Insert("ate".to_string())]
+                script.perform_edits(&context, &HashMap::new(), Coordinates::Offset),
+                Err(PerformError::Failed(KybError::Aborted("read".to_string())))
             );
         }
 
         #[test]
-        fn multiple_occurrences() {
-            let script = parse(r#"let region = find("r"); region.replace("t");"#).unwrap();
+        fn file_properties() {
+            let root = workspace("file_properties", &[("src/a.rs", "a\n a")]);
+            let script = parse(
+                r#"
+                let r = find("x");
+                for f in files("*.rs") {
+                    for found in f.find_all("a") {
+                        if found.line == 1 { r.insert_after(f.path); }
+                    }
+                    r.insert_after(f.text);
+                }"#,
+            )
+            .unwrap();
 
             assert_eq!(
-                script.perform(&context(&["rrr", "", "r"])).unwrap(),
-                vec![Mutation::Delete(1), Mutation::Insert("t".to_string())]
+                script
+                    .perform(&context_in(&root, &["x"]), &HashMap::new())
+                    .unwrap(),
+                vec![
+                    Mutation::Delete(1),
+                    Mutation::Insert("xsrc/a.rsa\n a".to_string())
+                ]
+            );
+
+            let script =
+                parse(r#"for f in files("*.rs") { find("x").replace_with(f.size); }"#).unwrap();
+            assert_eq!(
+                script.perform(&context_in(&root, &["x"]), &HashMap::new()),
+                Err(PerformError::Failed(KybError::UnknownProperty {
+                    receiver: "file",
+                    property: "size".to_string()
+                }))
             );
         }
     }
 
+    #[test]
+    fn mutations_() {
+        let edit = |range: Range<usize>, text: &str| Edit {
+            range,
+            text: text.to_string(),
+            file: None,
+        };
+        let contents = context(&["a", "", "bc"]);
+        let contents = contents.contents_ref();
+
+        assert_eq!(
+            mutations(&[], &contents, PositionEncoding::Utf8).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            mutations(&[edit(0..1, "x")], &contents, PositionEncoding::Utf8).unwrap(),
+            vec![Mutation::Backspace(1), Mutation::Insert("x".to_string())]
+        );
+        assert_eq!(
+            mutations(
+                &[edit(2..3, "z"), edit(0..1, "x")],
+                &contents,
+                PositionEncoding::Utf8
+            )
+            .unwrap(),
+            vec![
+                Mutation::Delete(2),
+                Mutation::Backspace(1),
+                Mutation::Insert("xbz".to_string())
+            ]
+        );
+        assert_eq!(
+            mutations(
+                &[edit(0..2, "x"), edit(1..3, "y")],
+                &contents,
+                PositionEncoding::Utf8
+            ),
+            None
+        );
+    }
+
     #[test]
     fn delete_range_() {
+        let delete_range =
+            |to_delete, selected| delete_range(to_delete, selected, "abcd", PositionEncoding::Utf8);
         assert_eq!(delete_range(0..1, 0..0).unwrap(), (1, 0));
         assert_eq!(delete_range(0..2, 0..0).unwrap(), (2, 0));
         assert_eq!(delete_range(1..2, 1..1).unwrap(), (1, 0));
@@ -450,4 +3373,16 @@ mod tests {
 
         assert_eq!(delete_range(1..3, 0..0), None);
     }
+
+    #[test]
+    fn delete_range_encodings() {
+        let contents = "é🦀x";
+        for (encoding, counts) in [
+            (PositionEncoding::Utf8, (5, 2)),
+            (PositionEncoding::Utf16, (3, 1)),
+            (PositionEncoding::Utf32, (2, 1)),
+        ] {
+            assert_eq!(delete_range(0..7, 2..2, contents, encoding), Some(counts));
+        }
+    }
 }
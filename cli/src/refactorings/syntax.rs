@@ -0,0 +1,227 @@
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::OnceLock,
+};
+use tree_sitter::{Language, Node, Parser};
+
+/// The syntax nodes of a text, by the offset they start at.
+///
+/// Texts are parsed as Rust, the only language with a bundled grammar, so callers check
+/// [`has_grammar`] for the text's language first.
+#[derive(Debug)]
+pub struct SyntaxTree {
+    text: String,
+    nodes: HashMap<usize, Vec<SyntaxNode>>,
+}
+
+#[derive(Debug)]
+pub struct SyntaxNode {
+    pub kind: &'static str,
+    pub range: Range<usize>,
+    /// Children in named fields, like `left` and `right` of a `binary_expression`.
+    pub fields: Vec<(String, Range<usize>)>,
+}
+
+impl SyntaxTree {
+    pub fn parse(text: &str) -> Self {
+        let mut parser = Parser::new();
+        parser.set_language(&language()).unwrap();
+
+        let mut nodes: HashMap<usize, Vec<SyntaxNode>> = HashMap::new();
+        if let Some(tree) = parser.parse(text, None) {
+            // Walks the tree in pre-order, so nodes starting at the same offset are outermost
+            // first.
+            let mut cursor = tree.walk();
+            'walk: loop {
+                let node = cursor.node();
+                nodes
+                    .entry(node.start_byte())
+                    .or_default()
+                    .push(SyntaxNode::new(&node));
+
+                if cursor.goto_first_child() {
+                    continue;
+                }
+                while !cursor.goto_next_sibling() {
+                    if !cursor.goto_parent() {
+                        break 'walk;
+                    }
+                }
+            }
+        }
+
+        SyntaxTree {
+            text: text.to_string(),
+            nodes,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Nodes starting at `start` of `kind`, or of any of its subtypes if it is a supertype like
+    /// `_expression`, outermost first.
+    pub fn nodes_at<'a>(
+        &'a self,
+        start: usize,
+        kind: &'a str,
+    ) -> impl Iterator<Item = &'a SyntaxNode> + 'a {
+        let subtypes = supertypes().get(kind);
+        self.nodes
+            .get(&start)
+            .into_iter()
+            .flatten()
+            .filter(move |n| n.kind == kind || subtypes.is_some_and(|s| s.contains(n.kind)))
+    }
+}
+
+impl SyntaxNode {
+    fn new(node: &Node) -> Self {
+        let mut fields = Vec::new();
+        let mut cursor = node.walk();
+        if cursor.goto_first_child() {
+            loop {
+                if let Some(name) = cursor.field_name() {
+                    fields.push((name.to_string(), cursor.node().byte_range()));
+                }
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+
+        SyntaxNode {
+            kind: node.kind(),
+            range: node.byte_range(),
+            fields,
+        }
+    }
+}
+
+/// Whether texts of the language `language_id` can be parsed.
+pub fn has_grammar(language_id: &str) -> bool {
+    language_id == "rust"
+}
+
+/// The language of a file from its extension, for files the editor didn't give a language for.
+pub fn language_of_path(path: &str) -> Option<&'static str> {
+    path.ends_with(".rs").then_some("rust")
+}
+
+/// Whether the grammar has nodes of `kind`.
+pub fn is_kind(kind: &str) -> bool {
+    let language = language();
+    language.id_for_node_kind(kind, true) != 0
+        || language.id_for_node_kind(kind, false) != 0
+        || supertypes().contains_key(kind)
+}
+
+fn language() -> Language {
+    tree_sitter_rust::LANGUAGE.into()
+}
+
+/// Every kind of node each supertype stands for, including through other supertypes.
+fn supertypes() -> &'static HashMap<String, HashSet<&'static str>> {
+    #[derive(Deserialize)]
+    struct NodeType {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        subtypes: Vec<NodeType>,
+    }
+
+    static SUPERTYPES: OnceLock<HashMap<String, HashSet<&'static str>>> = OnceLock::new();
+    SUPERTYPES.get_or_init(|| {
+        let node_types: Vec<NodeType> = serde_json::from_str(tree_sitter_rust::NODE_TYPES).unwrap();
+        let direct = node_types
+            .iter()
+            .filter(|t| !t.subtypes.is_empty())
+            .map(|t| (t.kind.as_str(), &t.subtypes))
+            .collect::<HashMap<_, _>>();
+
+        let language = language();
+        let mut supertypes = HashMap::new();
+        for &supertype in direct.keys() {
+            let mut kinds = HashSet::new();
+            let mut pending = vec![supertype];
+            while let Some(kind) = pending.pop() {
+                for subtype in direct[kind] {
+                    match direct.get(subtype.kind.as_str()) {
+                        Some(_) => pending.push(&subtype.kind),
+                        None => {
+                            // Node kinds of the grammar live as long as the program.
+                            let id = language.id_for_node_kind(&subtype.kind, true);
+                            if let Some(kind) = language.node_kind_for_id(id) {
+                                kinds.insert(kind);
+                            }
+                        }
+                    }
+                }
+            }
+            supertypes.insert(supertype.to_string(), kinds);
+        }
+        supertypes
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds_at(text: &str, start: usize, kind: &str) -> Vec<(&'static str, Range<usize>)> {
+        SyntaxTree::parse(text)
+            .nodes_at(start, kind)
+            .map(|n| (n.kind, n.range.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn outermost_first() {
+        assert_eq!(
+            kinds_at("a + b + c;", 0, "binary_expression"),
+            vec![("binary_expression", 0..9), ("binary_expression", 0..5)]
+        );
+        assert_eq!(kinds_at("a + b + c;", 4, "binary_expression"), vec![]);
+    }
+
+    #[test]
+    fn supertypes_() {
+        assert_eq!(
+            kinds_at("f(x) != 1;", 0, "_expression"),
+            vec![
+                ("binary_expression", 0..9),
+                ("call_expression", 0..4),
+                ("identifier", 0..1)
+            ]
+        );
+        assert_eq!(
+            kinds_at("f(x) != 1;", 8, "_expression"),
+            vec![("integer_literal", 8..9)]
+        );
+    }
+
+    #[test]
+    fn fields() {
+        let tree = SyntaxTree::parse("a != b;");
+        let node = tree.nodes_at(0, "binary_expression").next().unwrap();
+        assert_eq!(
+            node.fields,
+            vec![
+                ("left".to_string(), 0..1),
+                ("operator".to_string(), 2..4),
+                ("right".to_string(), 5..6)
+            ]
+        );
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(is_kind("binary_expression"));
+        assert!(is_kind("_expression"));
+        assert!(is_kind("!="));
+        assert!(!is_kind("binary_expr"));
+    }
+}
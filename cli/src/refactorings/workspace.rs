@@ -0,0 +1,238 @@
+use super::{Position, TextEdit};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A file of the workspace, read from disk.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WorkspaceFile {
+    /// Path from the workspace root, with `/` between components.
+    pub path: String,
+    pub full_path: PathBuf,
+    pub text: String,
+}
+
+/// Files under `root` matching `glob`, in order of their paths, leaving out `skip`.
+///
+/// Hidden files and directories are never searched, and neither are `target` directories of
+/// build output. Files that aren't UTF-8 text are left out.
+pub fn files(root: &Path, glob: &str, skip: Option<&Path>) -> io::Result<Vec<WorkspaceFile>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') || name == "target" {
+                continue;
+            }
+
+            let full_path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(full_path);
+                continue;
+            }
+
+            let path = full_path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if !glob_matches(glob, &path) || skip.is_some_and(|s| is_same_file(s, &full_path)) {
+                continue;
+            }
+            if let Ok(text) = fs::read_to_string(&full_path) {
+                files.push(WorkspaceFile {
+                    path,
+                    full_path,
+                    text,
+                });
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Whether `path`, from the workspace root, is matched by `glob`.
+///
+/// `*` stands for any run of characters in a component, `?` for any one, and `**` for any number
+/// of whole components. Globs of a single component like `*.rs` match the file name in any
+/// directory, and others the whole path.
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    let mut re = String::from(if glob.contains('/') { "^" } else { "(^|/)" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re += "(.*/)?";
+                } else {
+                    re += ".*";
+                }
+            }
+            '*' => re += "[^/]*",
+            '?' => re += "[^/]",
+            c => re += &regex::escape(c.encode_utf8(&mut [0; 4])),
+        }
+    }
+    re += "$";
+    regex::Regex::new(&re)
+        .unwrap()
+        .is_match(&path.replace('\\', "/"))
+}
+
+/// The root of the workspace holding `path`: the nearest directory above it with a `.git`, or
+/// failing that the highest with a `Cargo.toml`, so members of a Cargo workspace share its root.
+pub fn root_of(path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    let mut cargo_root = None;
+    for dir in path.ancestors().skip(1) {
+        if dir.join(".git").exists() {
+            return Some(dir.to_path_buf());
+        }
+        if dir.join("Cargo.toml").is_file() {
+            cargo_root = Some(dir.to_path_buf());
+        }
+    }
+    cargo_root
+}
+
+/// Makes `edits`, in order and with offsets in bytes, to the file at `path`.
+pub fn write_edits(path: &Path, edits: &[TextEdit]) -> io::Result<()> {
+    let mut text = fs::read_to_string(path)?;
+    for edit in edits.iter().rev() {
+        match (edit.range.start, edit.range.end) {
+            (Position::Offset(start), Position::Offset(end)) => {
+                text.replace_range(start..end, &edit.new_text)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Edits to write need offsets",
+                ))
+            }
+        }
+    }
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::refactorings::TextRange;
+
+    /// A new directory holding `files`, by path from it.
+    pub fn workspace(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("kyber-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (path, text) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*.rs", "src/main.rs"));
+        assert!(glob_matches("**/*.rs", "main.rs"));
+        assert!(glob_matches("**/*.rs", "src/a/b.rs"));
+        assert!(glob_matches("src/**", "src/a/b.rs"));
+        assert!(glob_matches("src/*.rs", "src/a.rs"));
+        assert!(!glob_matches("src/*.rs", "vendor/x/src/a.rs"));
+        assert!(!glob_matches("src/*.rs", "src/a/b.rs"));
+        assert!(!glob_matches("*.rs", "main.rs.orig"));
+        assert!(!glob_matches("?.rs", "ab.rs"));
+    }
+
+    #[test]
+    fn files_() {
+        let root = workspace(
+            "files",
+            &[
+                ("main.rs", ""),
+                ("src/b.rs", "b"),
+                ("src/a.rs", "a"),
+                ("src/notes.md", ""),
+                (".git/x.rs", ""),
+                ("target/debug/out.rs", ""),
+            ],
+        );
+
+        let paths = |skip: Option<&Path>| {
+            files(&root, "**/*.rs", skip)
+                .unwrap()
+                .into_iter()
+                .map(|f| f.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(None), vec!["main.rs", "src/a.rs", "src/b.rs"]);
+        assert_eq!(
+            paths(Some(&root.join("src/../main.rs"))),
+            vec!["src/a.rs", "src/b.rs"]
+        );
+        assert_eq!(files(&root, "src/a.rs", None).unwrap()[0].text, "a");
+    }
+
+    #[test]
+    fn root_of_() {
+        let root = workspace(
+            "root_of",
+            &[
+                ("git/.git/HEAD", ""),
+                ("git/crate/Cargo.toml", ""),
+                ("git/crate/src/lib.rs", ""),
+                ("cargo/Cargo.toml", ""),
+                ("cargo/member/Cargo.toml", ""),
+                ("cargo/member/src/lib.rs", ""),
+                ("none/a.rs", ""),
+            ],
+        );
+        let root = fs::canonicalize(root).unwrap();
+
+        assert_eq!(
+            root_of(&root.join("git/crate/src/lib.rs")),
+            Some(root.join("git"))
+        );
+        assert_eq!(
+            root_of(&root.join("cargo/member/src/lib.rs")),
+            Some(root.join("cargo"))
+        );
+        assert_eq!(root_of(&root.join("none/a.rs")), None);
+        assert_eq!(root_of(&root.join("missing.rs")), None);
+    }
+
+    #[test]
+    fn write_edits_() {
+        let root = workspace("write_edits", &[("a.rs", "let a = b;")]);
+        let edit = |start, end, new_text: &str| TextEdit {
+            range: TextRange {
+                start: Position::Offset(start),
+                end: Position::Offset(end),
+            },
+            new_text: new_text.to_string(),
+        };
+
+        write_edits(&root.join("a.rs"), &[edit(4, 5, "xy"), edit(8, 9, "z")]).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("a.rs")).unwrap(),
+            "let xy = z;"
+        );
+    }
+}
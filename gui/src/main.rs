@@ -61,7 +61,7 @@ impl epi::App for App {
     }
 
     fn update(&mut self, ctx: &Context, _frame: &epi::Frame) {
-        if ctx.memory().focus() == None {
+        if ctx.memory().focus().is_none() {
             self.handle_actions(ctx);
         }
 
@@ -106,13 +106,11 @@ impl App {
         for event in &ctx.input().events {
             match event {
                 Event::Key {
-                    pressed,
+                    pressed: true,
                     key,
                     modifiers,
                 } => {
-                    if *pressed {
-                        self.keyboard_control.on_key(*key, *modifiers);
-                    }
+                    self.keyboard_control.on_key(*key, *modifiers);
                 }
                 Event::Text(s) => {
                     self.keyboard_control.on_text(s);
@@ -31,7 +31,7 @@ impl Screen for File {
             }
         };
 
-        let pos = self.cursor.byte_pos(&contents);
+        let pos = self.cursor.byte_pos(contents);
         let (pre, cursor, post) = (
             &contents[0..pos],
             &contents[pos..(pos + 1)],